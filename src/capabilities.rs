@@ -0,0 +1,386 @@
+use std::{
+    ffi::CStr,
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs,
+    mem::zeroed,
+};
+
+use libc;
+
+use count::Counted;
+use fd::{OpenError, OpenFlags, PerfFile};
+use raw::perf_event_attr;
+use {CpuConfig, EventConfig, PidConfig, SwEvent};
+
+/// What the running kernel allows this process to do with perf_event_open(2), from the sysctls
+/// under /proc/sys/kernel, the process's effective capabilities, and test opens of a dummy event.
+/// Each value is `None` if it couldn't be read.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// perf_event_paranoid, which restricts unprivileged users:
+    ///
+    /// * -1 - allow use of (almost) all events by all users
+    /// * 0 - disallow raw tracepoint access for unprivileged users
+    /// * 1 - also disallow CPU-wide events for unprivileged users
+    /// * 2 - also disallow kernel profiling for unprivileged users
+    /// * 3 or more - some distributions' patched kernels disallow perf_event_open entirely for
+    ///   unprivileged users
+    pub paranoid: Option<i32>,
+    /// perf_event_max_sample_rate, the highest `SamplingRate::Frequency` the kernel allows.
+    pub max_sample_rate: Option<u64>,
+    /// perf_event_mlock_kb, how much memory each user can lock for ring buffers (per CPU) beyond
+    /// RLIMIT_MEMLOCK.
+    pub mlock_kb: Option<usize>,
+    /// perf_event_max_stack, the deepest callchain the kernel records. (since Linux 4.8)
+    pub max_stack: Option<u32>,
+    /// Whether the process has CAP_PERFMON, which bypasses the paranoid checks. (since Linux 5.8)
+    pub cap_perfmon: bool,
+    /// Whether the process has CAP_SYS_ADMIN, which bypasses the paranoid checks.
+    pub cap_sys_admin: bool,
+    pub kernel_version: Option<KernelVersion>,
+    /// Which of the newer perf_event_attr fields the kernel accepted. Empty if the dummy event
+    /// couldn't be opened at all.
+    pub supported: Vec<AttrField>,
+}
+
+/// A perf_event_attr field which older kernels reject with EINVAL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+pub enum AttrField {
+    /// `SamplingConfig::mmap2` (since Linux 3.16)
+    Mmap2,
+    /// `SamplingConfig::comm_exec` (since Linux 3.16)
+    CommExec,
+    /// `EventConfig::clock` (since Linux 4.1)
+    UseClockid,
+    /// PERF_RECORD_SWITCH records (since Linux 4.3)
+    ContextSwitch,
+    /// Overwritable ring buffers, as used by `flight_recorder` (since Linux 4.7)
+    WriteBackward,
+    /// PERF_RECORD_NAMESPACES records (since Linux 4.12)
+    Namespaces,
+}
+
+impl AttrField {
+    fn all() -> Vec<Self> {
+        use self::AttrField::*;
+        vec![
+            Mmap2,
+            CommExec,
+            UseClockid,
+            ContextSwitch,
+            WriteBackward,
+            Namespaces,
+        ]
+    }
+
+    fn set(&self, attr: &mut perf_event_attr) {
+        match *self {
+            AttrField::Mmap2 => {
+                attr.set_mmap(1);
+                attr.set_mmap2(1);
+            }
+            AttrField::CommExec => {
+                attr.set_comm(1);
+                attr.set_comm_exec(1);
+            }
+            AttrField::UseClockid => {
+                attr.set_use_clockid(1);
+                attr.clockid = libc::CLOCK_MONOTONIC;
+            }
+            AttrField::ContextSwitch => attr.set_context_switch(1),
+            AttrField::WriteBackward => attr.set_write_backward(1),
+            AttrField::Namespaces => attr.set_namespaces(1),
+        }
+    }
+
+    fn is_set(&self, attr: &perf_event_attr) -> bool {
+        match *self {
+            AttrField::Mmap2 => attr.mmap2() != 0,
+            AttrField::CommExec => attr.comm_exec() != 0,
+            AttrField::UseClockid => attr.use_clockid() != 0,
+            AttrField::ContextSwitch => attr.context_switch() != 0,
+            AttrField::WriteBackward => attr.write_backward() != 0,
+            AttrField::Namespaces => attr.namespaces() != 0,
+        }
+    }
+}
+
+/// A kernel release, parsed from uname(2).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl KernelVersion {
+    /// Parses the leading numbers of a release like "5.15.0-91-generic".
+    fn parse(release: &str) -> Option<Self> {
+        let mut numbers = release
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|n| n.parse());
+        Some(KernelVersion {
+            major: numbers.next()?.ok()?,
+            minor: numbers.next()?.ok()?,
+            patch: numbers.next().and_then(|n| n.ok()).unwrap_or(0),
+        })
+    }
+}
+
+impl Display for KernelVersion {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// bits of the capability sets in /proc/self/status
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
+
+impl Capabilities {
+    /// Reads the system's configuration and tries opening a dummy software event on the calling
+    /// thread with each of the `AttrField`s set.
+    pub fn probe() -> Self {
+        let mut capabilities = Self::read_system();
+        capabilities.supported = AttrField::all()
+            .into_iter()
+            .filter(|field| {
+                let mut attr = dummy_attr();
+                field.set(&mut attr);
+                PerfFile::open_raw(&attr, 0, CpuConfig::All, -1, OpenFlags::default()).is_ok()
+            })
+            .collect();
+        capabilities
+    }
+
+    /// Reads the sysctls, capabilities and kernel version, without opening any events.
+    pub(crate) fn read_system() -> Self {
+        let sysctl = |name: &str| {
+            fs::read_to_string(format!("/proc/sys/kernel/{}", name))
+                .ok()
+                .map(|value| value.trim().to_owned())
+        };
+
+        let effective = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find(|line| line.starts_with("CapEff:"))
+                    .and_then(|line| u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok())
+            })
+            .unwrap_or(0);
+
+        Capabilities {
+            paranoid: sysctl("perf_event_paranoid").and_then(|v| v.parse().ok()),
+            max_sample_rate: sysctl("perf_event_max_sample_rate").and_then(|v| v.parse().ok()),
+            mlock_kb: sysctl("perf_event_mlock_kb").and_then(|v| v.parse().ok()),
+            max_stack: sysctl("perf_event_max_stack").and_then(|v| v.parse().ok()),
+            cap_perfmon: effective & (1 << CAP_PERFMON) != 0,
+            cap_sys_admin: effective & (1 << CAP_SYS_ADMIN) != 0,
+            kernel_version: kernel_release().and_then(|r| KernelVersion::parse(&r)),
+            supported: Vec::new(),
+        }
+    }
+
+    /// Whether the process bypasses the paranoid checks.
+    pub fn privileged(&self) -> bool {
+        self.cap_perfmon || self.cap_sys_admin
+    }
+
+    /// Suggests how to avoid an error from opening an event with `attr` for `pid`, if there's a
+    /// likely cause in the system's configuration.
+    pub fn hint(
+        &self,
+        error: &OpenError,
+        attr: &perf_event_attr,
+        pid: &PidConfig,
+    ) -> Option<String> {
+        let paranoid = self.paranoid;
+        let cpu_wide = matches!(*pid, PidConfig::Cgroup(_) | PidConfig::Other(-1));
+
+        match *error {
+            OpenError::CapSysAdminRequired | OpenError::CapSysAdminRequiredOrExcludeUnsupported
+                if !self.privileged() =>
+            {
+                let fix = "or run with CAP_PERFMON (or CAP_SYS_ADMIN before Linux 5.8)";
+                match paranoid {
+                    Some(level) if level > 2 => Some(format!(
+                        "kernel.perf_event_paranoid is {}, which disables perf_event_open for \
+                         unprivileged users: set it to 2 or lower, {}",
+                        level, fix
+                    )),
+                    Some(level) if level > 0 && cpu_wide => Some(format!(
+                        "kernel.perf_event_paranoid is {}, which disallows measuring whole CPUs \
+                         or cgroups: set it to 0 or lower, {}",
+                        level, fix
+                    )),
+                    Some(level) if level > 1 && attr.exclude_kernel() == 0 => Some(format!(
+                        "kernel.perf_event_paranoid is {}, which disallows measuring the kernel: \
+                         set EventConfig::exclude_kernel, set the sysctl to 1 or lower, {}",
+                        level, fix
+                    )),
+                    _ => None,
+                }
+            }
+            OpenError::InvalidEventType | OpenError::HardwareFeatureUnsupported
+                if attr.type_ != ::raw::perf_type_id::PERF_TYPE_SOFTWARE =>
+            {
+                Some(String::from(
+                    "this CPU has no PMU for the event, which is common in virtual machines: \
+                     try a software event such as SwEvent::TaskClock",
+                ))
+            }
+            OpenError::InvalidEvent => {
+                let freq = attr.freq() != 0;
+                // NOTE(unsafe) sample_freq and sample_period share a u64
+                let rate = unsafe { attr.__bindgen_anon_1.sample_freq };
+                if let Some(max) = self.max_sample_rate.filter(|&max| freq && rate > max) {
+                    return Some(format!(
+                        "the sampling frequency {}Hz is above kernel.perf_event_max_sample_rate \
+                         ({}Hz)",
+                        rate, max
+                    ));
+                }
+
+                // only probe for fields when they might be the problem
+                let set = AttrField::all()
+                    .into_iter()
+                    .filter(|field| field.is_set(attr))
+                    .collect::<Vec<_>>();
+                if set.is_empty() {
+                    return None;
+                }
+                let supported = Self::probe().supported;
+                let unsupported = set
+                    .into_iter()
+                    .filter(|field| !supported.contains(field))
+                    .collect::<Vec<_>>();
+                if unsupported.is_empty() {
+                    None
+                } else {
+                    Some(format!(
+                        "this kernel ({}) doesn't support {:?}",
+                        self.kernel_version
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|| "unknown version".into()),
+                        unsupported
+                    ))
+                }
+            }
+            OpenError::SampleMaxStackTooLarge => self.max_stack.map(|max| {
+                format!(
+                    "callchains are limited to kernel.perf_event_max_stack ({}) frames",
+                    max
+                )
+            }),
+            OpenError::TooManyOpenFiles => Some(String::from(
+                "each event needs a file descriptor: raise RLIMIT_NOFILE (ulimit -n)",
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Advice for fixing an error, from the system's perf_event configuration, as returned by
+/// `Error::hint`. Displays as " (hint: ...)", or nothing if there isn't one.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Hint(pub Option<String>);
+
+impl Hint {
+    /// Reads the system's configuration to explain why opening an event failed.
+    pub(crate) fn for_open(error: &OpenError, attr: &perf_event_attr, pid: &PidConfig) -> Self {
+        Hint(Capabilities::read_system().hint(error, attr, pid))
+    }
+}
+
+impl Display for Hint {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self.0 {
+            Some(ref hint) => write!(f, " (hint: {})", hint),
+            None => Ok(()),
+        }
+    }
+}
+
+fn dummy_attr() -> perf_event_attr {
+    let shared = EventConfig {
+        exclude_kernel: true,
+        exclude_hv: true,
+        ..EventConfig::default()
+    };
+    let mut attr = shared.raw();
+    ::fd::PerfEventAttrThingy::apply(&Counted::Software(SwEvent::DummyForSampled), &mut attr);
+    attr
+}
+
+fn kernel_release() -> Option<String> {
+    // NOTE(unsafe) uname only writes to the struct, and its fields are NUL-terminated
+    unsafe {
+        let mut name: libc::utsname = zeroed();
+        if libc::uname(&mut name) != 0 {
+            return None;
+        }
+        Some(
+            CStr::from_ptr(name.release.as_ptr())
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_this_system() {
+        let capabilities = Capabilities::probe();
+        assert!(capabilities.paranoid.is_some());
+        assert!(capabilities.kernel_version.unwrap().major >= 3);
+        assert!(capabilities.supported.contains(&AttrField::Mmap2));
+
+        assert_eq!(
+            KernelVersion::parse("5.15.0-91-generic"),
+            Some(KernelVersion {
+                major: 5,
+                minor: 15,
+                patch: 0
+            })
+        );
+        assert_eq!(KernelVersion::parse("6.1").map(|v| v.patch), Some(0));
+    }
+
+    #[test]
+    fn hints() {
+        let mut attr = dummy_attr();
+        attr.set_exclude_kernel(0);
+        let capabilities = Capabilities {
+            paranoid: Some(2),
+            max_sample_rate: Some(1000),
+            ..Capabilities::default()
+        };
+        let hint = |error, attr: &perf_event_attr, pid| capabilities.hint(&error, attr, &pid);
+
+        let denied = hint(OpenError::CapSysAdminRequired, &attr, PidConfig::Current);
+        assert!(denied.unwrap().contains("exclude_kernel"));
+        let denied = hint(OpenError::CapSysAdminRequired, &attr, PidConfig::Other(-1));
+        assert!(denied.unwrap().contains("whole CPUs"));
+        assert_eq!(
+            hint(OpenError::ProcessDoesNotExist, &attr, PidConfig::Current),
+            None
+        );
+
+        attr.set_freq(1);
+        attr.__bindgen_anon_1.sample_freq = 4000;
+        let invalid = hint(OpenError::InvalidEvent, &attr, PidConfig::Current);
+        assert!(invalid.unwrap().contains("perf_event_max_sample_rate"));
+
+        let privileged = Capabilities {
+            cap_perfmon: true,
+            ..capabilities.clone()
+        };
+        let error = OpenError::CapSysAdminRequired;
+        assert_eq!(privileged.hint(&error, &attr, &PidConfig::Current), None);
+    }
+}
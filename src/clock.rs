@@ -0,0 +1,231 @@
+use std::{
+    mem::zeroed,
+    ptr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libc::{self, clockid_t};
+use nix::errno::Errno;
+use page_size::get as page_size;
+
+use error::*;
+use sample::{
+    blocking::BlockingSampler,
+    config::{BufferSize, SampleRequest, SamplingConfig, SamplingRate},
+    record::RecordContents,
+};
+use {Counted, EventConfig, SwEvent};
+
+/// The Linux clocks which perf_event_open can use for timestamps instead of its own perf_clock.
+/// (since Linux 4.1)
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Clock {
+    /// CLOCK_MONOTONIC, the clock Rust's `Instant` uses on Linux.
+    Monotonic,
+    /// CLOCK_MONOTONIC_RAW, which isn't subject to NTP adjustments.
+    MonotonicRaw,
+    /// CLOCK_REALTIME, wall-clock time since the Unix epoch.
+    Realtime,
+    /// CLOCK_BOOTTIME, which is like CLOCK_MONOTONIC but includes time spent suspended.
+    Boottime,
+    /// CLOCK_TAI, International Atomic Time, which doesn't have leap seconds.
+    Tai,
+}
+
+impl Clock {
+    pub(crate) fn raw(&self) -> clockid_t {
+        match *self {
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::MonotonicRaw => libc::CLOCK_MONOTONIC_RAW,
+            Clock::Realtime => libc::CLOCK_REALTIME,
+            Clock::Boottime => libc::CLOCK_BOOTTIME,
+            Clock::Tai => libc::CLOCK_TAI,
+        }
+    }
+
+    /// Reads the clock's current value in nanoseconds, in the same units as a sample timestamp.
+    pub fn now(&self) -> u64 {
+        clock_gettime(self.raw())
+    }
+}
+
+fn clock_gettime(clock: clockid_t) -> u64 {
+    // NOTE(unsafe) these clocks are always supported on kernels with perf_event_open
+    let mut time: libc::timespec = unsafe { zeroed() };
+    unsafe { libc::clock_gettime(clock, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// The offsets between the clock used for an event's timestamps and the system's wall and
+/// monotonic clocks, for converting sample timestamps to `SystemTime`s and `Instant`s. This makes
+/// it possible to line up samples with application logs.
+///
+/// The clocks drift apart slowly (e.g. while NTP adjusts CLOCK_REALTIME), so long sessions should
+/// probe again periodically.
+#[derive(Clone, Copy, Debug)]
+pub struct ClockCorrelation {
+    /// CLOCK_REALTIME minus the event clock, in nanoseconds.
+    realtime_offset: i64,
+    /// CLOCK_MONOTONIC minus the event clock, in nanoseconds.
+    monotonic_offset: i64,
+    /// An `Instant` and the CLOCK_MONOTONIC value it was taken at.
+    instant: Instant,
+    monotonic: u64,
+}
+
+impl ClockCorrelation {
+    /// Measures the offsets for timestamps from events configured with `clock`. For the default
+    /// perf_clock (`None`) this opens a page fault sampler on the calling thread and takes a page
+    /// fault, since perf_clock can't be read from user space directly.
+    pub fn probe(clock: Option<Clock>) -> Result<Self> {
+        let (before, timestamp, after) = match clock {
+            Some(clock) => {
+                let before = Reading::now();
+                let timestamp = clock.now();
+                (before, timestamp, Reading::now())
+            }
+            None => probe_perf_clock()?,
+        };
+
+        let midpoint = |a: u64, b: u64| (a / 2 + b / 2) as i64;
+        Ok(Self {
+            realtime_offset: midpoint(before.realtime, after.realtime) - timestamp as i64,
+            monotonic_offset: midpoint(before.monotonic, after.monotonic) - timestamp as i64,
+            instant: after.instant,
+            monotonic: after.monotonic,
+        })
+    }
+
+    /// CLOCK_REALTIME minus the event clock, in nanoseconds.
+    pub fn realtime_offset(&self) -> i64 {
+        self.realtime_offset
+    }
+
+    /// Converts a timestamp from a record (e.g. `Sample::time`) to wall-clock time.
+    pub fn to_system_time(&self, timestamp: u64) -> SystemTime {
+        let realtime = (timestamp as i64 + self.realtime_offset) as u64;
+        UNIX_EPOCH + Duration::from_nanos(realtime)
+    }
+
+    /// Converts a timestamp from a record (e.g. `Sample::time`) to an `Instant`, to compare with
+    /// times measured by the application.
+    pub fn to_instant(&self, timestamp: u64) -> Instant {
+        let monotonic = timestamp as i64 + self.monotonic_offset;
+        let since_probe = monotonic - self.monotonic as i64;
+        if since_probe >= 0 {
+            self.instant + Duration::from_nanos(since_probe as u64)
+        } else {
+            self.instant - Duration::from_nanos(-since_probe as u64)
+        }
+    }
+}
+
+struct Reading {
+    realtime: u64,
+    monotonic: u64,
+    instant: Instant,
+}
+
+impl Reading {
+    fn now() -> Self {
+        Self {
+            realtime: clock_gettime(libc::CLOCK_REALTIME),
+            monotonic: clock_gettime(libc::CLOCK_MONOTONIC),
+            instant: Instant::now(),
+        }
+    }
+}
+
+/// Takes a page fault between two readings of the system clocks, returning the readings and the
+/// fault's perf_clock timestamp.
+fn probe_perf_clock() -> Result<(Reading, u64, Reading)> {
+    let config = SamplingConfig {
+        shared: EventConfig::default(),
+        event: Counted::Software(SwEvent::PageFaults),
+        rate: SamplingRate::Period(1),
+        requests: vec![SampleRequest::Time, SampleRequest::Address],
+        buffer_size: BufferSize::Pages(1),
+        sample_id_all: false,
+        mmap: false,
+        mmap2: false,
+        comm: false,
+        comm_exec: false,
+        task: false,
+        enable_on_exec: false,
+        precise_ip: 0,
+        ..SamplingConfig::default()
+    };
+    let mut sampler = BlockingSampler::new(vec![config])?;
+
+    // NOTE(unsafe) a private anonymous page which is only written once and then unmapped
+    let page = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            page_size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if page == libc::MAP_FAILED {
+        return Err(Error::Posix {
+            call: "mmap",
+            inner: ::nix::Error::Sys(Errno::last()),
+        });
+    }
+
+    let before = Reading::now();
+    unsafe { ptr::write_volatile(page as *mut u8, 1) };
+    let after = Reading::now();
+
+    let mut timestamp = None;
+    let drained = sampler.drain(|record| {
+        if let RecordContents::Sample(sample) = record.contents {
+            if sample.addr == Some(page as u64) {
+                timestamp = sample.time;
+            }
+        }
+    });
+
+    // unmapped before checking whether the drain failed so the page isn't leaked
+    unsafe { libc::munmap(page, page_size()) };
+    drained?;
+
+    match timestamp {
+        Some(timestamp) => Ok((before, timestamp, after)),
+        None => Err(Error::Misc {
+            inner: "the probe's page fault wasn't sampled".into(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlates_clocks() {
+        for &clock in &[None, Some(Clock::Monotonic), Some(Clock::Realtime)] {
+            let correlation = ClockCorrelation::probe(clock).unwrap();
+
+            let timestamp = match clock {
+                Some(clock) => clock.now(),
+                None => probe_perf_clock().unwrap().1,
+            };
+
+            let wall = correlation.to_system_time(timestamp);
+            let skew = match SystemTime::now().duration_since(wall) {
+                Ok(behind) => behind,
+                Err(ahead) => ahead.duration(),
+            };
+            assert!(skew < Duration::from_millis(100), "{:?}: {:?}", clock, skew);
+
+            let instant = correlation.to_instant(timestamp);
+            assert!(Instant::now().duration_since(instant) < Duration::from_millis(100));
+        }
+
+        let correlation = ClockCorrelation::probe(Some(Clock::Realtime)).unwrap();
+        assert!(correlation.realtime_offset().abs() < 1_000_000);
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, prelude::*},
+    mem::size_of,
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::Duration,
+};
+
+use libc::{self, c_void, pid_t};
+use nix::{fcntl::OFlag, unistd::pipe2};
+
+use count::{CountConfig, Counted, Counter};
+use error::*;
+use sample::{blocking::BlockingSampler, config::SamplingConfig, record::Record, SessionStats};
+use {EventConfig, PidConfig};
+
+/// The exit status and final counts of a command run by `count_command`.
+#[derive(Debug)]
+pub struct CommandCounts {
+    pub status: ExitStatus,
+    pub counts: BTreeMap<Counted, u64>,
+}
+
+/// The exit status and records of a command run by `sample_command`.
+#[derive(Debug)]
+pub struct CommandSamples {
+    pub status: ExitStatus,
+    pub records: Vec<Record>,
+    pub stats: SessionStats,
+}
+
+/// Runs `command` to completion while counting `events` in it, like `perf stat -- cmd`.
+///
+/// The child is held between fork(2) and exec(2) while the counters are opened, and they're
+/// enabled by the kernel when it execs, so the counts cover the command from its first
+/// instruction without including any of this library's setup. `shared.pid` is ignored. Set
+/// `shared.inherit` to also count any threads and processes the command starts.
+///
+/// Returns an error if any of the counters can't be opened, in which case the command isn't run.
+pub fn count_command(
+    command: Command,
+    shared: EventConfig,
+    events: &[Counted],
+) -> Result<CommandCounts> {
+    let (mut child, mut counters) = spawn_gated(command, |pid| {
+        let shared = EventConfig {
+            pid: PidConfig::Other(pid),
+            ..shared
+        };
+        events
+            .iter()
+            .map(|&event| {
+                Counter::on_exec(CountConfig {
+                    event,
+                    shared: shared.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let status = child.wait().map_err(|inner| Error::Spawn { inner })?;
+
+    let mut counts = BTreeMap::new();
+    for counter in &mut counters {
+        let (event, count) = counter.read()?;
+        counts.insert(event, count);
+    }
+
+    Ok(CommandCounts { status, counts })
+}
+
+/// Runs `command` to completion while sampling it, like `perf record -- cmd`, reading the ring
+/// buffer on the calling thread until the command exits.
+///
+/// The configs' `enable_on_exec` is always set, and their `shared.pid` is replaced with the
+/// child's, for the same reasons as `count_command`.
+pub fn sample_command(command: Command, configs: Vec<SamplingConfig>) -> Result<CommandSamples> {
+    let (mut child, mut sampler) = spawn_gated(command, |pid| {
+        let configs = configs
+            .into_iter()
+            .map(|mut config| {
+                config.shared.pid = PidConfig::Other(pid);
+                config.enable_on_exec = true;
+                config
+            })
+            .collect();
+        BlockingSampler::open(vec![configs])
+    })?;
+
+    let mut records = Vec::new();
+    let status = loop {
+        let res = child
+            .try_wait()
+            .map_err(|inner| Error::Spawn { inner })
+            .and_then(|status| {
+                sampler.wait(Some(Duration::from_millis(10)))?;
+                sampler.drain(|record| records.push(record))?;
+                Ok(status)
+            });
+
+        match res {
+            Ok(Some(status)) => break status,
+            Ok(None) => continue,
+            Err(why) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(why);
+            }
+        }
+    };
+
+    Ok(CommandSamples {
+        status,
+        records,
+        stats: sampler.stats(),
+    })
+}
+
+/// Spawns `command`, holding the child before it calls exec(2) until `open` has returned
+/// successfully for its pid. If `open` fails the child exits without running the command.
+///
+/// `Command::spawn` doesn't return until the child has exec'd, so it runs on another thread
+/// while this one waits for the child to send its pid over a pipe.
+fn spawn_gated<T>(
+    mut command: Command,
+    open: impl FnOnce(pid_t) -> Result<T>,
+) -> Result<(Child, T)> {
+    let pipe = || {
+        pipe2(OFlag::O_CLOEXEC).map_err(|inner| Error::Posix {
+            call: "pipe2",
+            inner,
+        })
+    };
+    let (pid_rx, pid_tx) = pipe()?;
+    let (release_rx, release_tx) = pipe()?;
+
+    // NOTE(unsafe) the pipes' fds are each owned by exactly one of these files
+    let (mut pid_rx, pid_tx, release_rx, mut release_tx) = unsafe {
+        (
+            File::from_raw_fd(pid_rx),
+            File::from_raw_fd(pid_tx),
+            File::from_raw_fd(release_rx),
+            File::from_raw_fd(release_tx),
+        )
+    };
+
+    {
+        let (pid_rx, pid_tx) = (pid_rx.as_raw_fd(), pid_tx.as_raw_fd());
+        let (release_rx, release_tx) = (release_rx.as_raw_fd(), release_tx.as_raw_fd());
+
+        // NOTE(unsafe) this runs in the forked child, and only makes async-signal-safe calls
+        unsafe {
+            command.pre_exec(move || {
+                // otherwise the child would keep the pipes open if the parent dropped its ends
+                libc::close(pid_rx);
+                libc::close(release_tx);
+
+                let pid = libc::getpid();
+                let pid_ptr = &pid as *const pid_t as *const c_void;
+                if libc::write(pid_tx, pid_ptr, size_of::<pid_t>()) != size_of::<pid_t>() as isize {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let mut released = 0u8;
+                loop {
+                    let res = libc::read(release_rx, &mut released as *mut u8 as *mut c_void, 1);
+                    if res == 1 {
+                        return Ok(());
+                    } else if res == 0 {
+                        return Err(io::Error::from_raw_os_error(libc::ECANCELED));
+                    } else if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            });
+        }
+    }
+
+    thread::scope(|scope| {
+        let spawned = scope.spawn(move || {
+            let res = command.spawn();
+            // the child has exec'd or exited, and has its own copies of these if it needed them
+            drop((pid_tx, release_rx));
+            res
+        });
+
+        let mut pid = [0u8; size_of::<pid_t>()];
+        let opened = match pid_rx.read_exact(&mut pid) {
+            Ok(()) => open(pid_t::from_ne_bytes(pid)),
+            Err(inner) => Err(Error::Spawn { inner }),
+        };
+
+        // the child execs once it reads this byte, or exits once it sees the pipe close
+        let opened = opened.and_then(|opened| match release_tx.write_all(&[1]) {
+            Ok(()) => Ok(opened),
+            Err(inner) => Err(Error::Spawn { inner }),
+        });
+        drop(release_tx);
+
+        let spawned = spawned.join().expect("spawning thread panicked");
+        match (spawned, opened) {
+            (Ok(child), Ok(opened)) => Ok((child, opened)),
+            (Ok(mut child), Err(why)) => {
+                let _ = child.wait();
+                Err(why)
+            }
+            (Err(_), Err(why)) => Err(why),
+            (Err(inner), Ok(_)) => Err(Error::Spawn { inner }),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sample::config::SamplingRate;
+    use sample::record::RecordContents;
+    use SwEvent;
+
+    #[test]
+    fn counts_a_command() {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done; exit 3",
+        ]);
+
+        let events = [
+            Counted::Software(SwEvent::TaskClock),
+            Counted::Software(SwEvent::ContextSwitches),
+        ];
+        let measured = count_command(command, EventConfig::default(), &events).unwrap();
+
+        assert_eq!(measured.status.code(), Some(3));
+        assert_eq!(measured.counts.len(), 2);
+        assert!(measured.counts[&Counted::Software(SwEvent::TaskClock)] > 0);
+    }
+
+    #[test]
+    fn missing_command_fails() {
+        let command = Command::new("/nonexistent/command");
+        let events = [Counted::Software(SwEvent::TaskClock)];
+        assert!(count_command(command, EventConfig::default(), &events).is_err());
+    }
+
+    #[test]
+    fn samples_a_command() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done"]);
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+        let measured = sample_command(command, vec![config]).unwrap();
+
+        assert!(measured.status.success());
+        let samples = measured
+            .records
+            .iter()
+            .filter(|r| matches!(r.contents, RecordContents::Sample(_)))
+            .count();
+        assert_ne!(samples, 0);
+        assert_eq!(measured.stats.samples as usize, samples);
+    }
+}
@@ -1,9 +1,11 @@
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::prelude::*;
 use std::mem::size_of;
-use std::slice;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
 
-use serde::{Serialize, Serializer};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use strum::IntoEnumIterator;
 
 use raw::perf_hw_cache_id::*;
@@ -16,15 +18,20 @@ use raw::{perf_event_attr, perf_type_id};
 
 use super::{CpuConfig, EventConfig, PidConfig};
 use error::*;
-use fd::{PerfEventAttrThingy, PerfFile};
+use fd::{AttrExtension, PerfEventAttrThingy, PerfFile};
+use process::online_cpus;
+use raw::perf_event_read_format::*;
+use sample::record::ReadValues;
+use self_monitor::PerfClock;
 
 #[derive(Debug)]
 pub struct Counter {
     config: CountConfig,
-    file: PerfFile,
+    /// A single file, or one per online CPU for a cgroup, whose counts are summed.
+    files: Vec<PerfFile>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CountConfig {
     pub event: Counted,
     pub shared: EventConfig,
@@ -52,32 +59,113 @@ impl AsRef<PidConfig> for CountConfig {
 
 impl Counter {
     pub fn new(config: CountConfig) -> Result<Self> {
-        let file = PerfFile::new(config)?;
-        Ok(Self { config, file })
+        let attr = config.clone().into();
+        Self::open(config, &attr)
     }
 
+    /// Opens a counter which the kernel enables once its task calls exec(2).
+    pub(crate) fn on_exec(config: CountConfig) -> Result<Self> {
+        let mut attr: perf_event_attr = config.clone().into();
+        attr.set_enable_on_exec(1);
+        Self::open(config, &attr)
+    }
+
+    fn open(config: CountConfig, attr: &perf_event_attr) -> Result<Self> {
+        let mut attr = *attr;
+        attr.read_format |= Self::READ_FORMAT;
+        let attr = &attr;
+
+        let (pid, cpu, flags) = (&config.shared.pid, config.shared.cpu, config.shared.flags);
+        let files = match (pid, cpu) {
+            (&PidConfig::Cgroup(_), CpuConfig::All) => online_cpus()?
+                .into_iter()
+                .map(|cpu| PerfFile::open(attr, pid, CpuConfig::Specific(cpu), flags))
+                .collect::<Result<Vec<_>>>()?,
+            _ => vec![PerfFile::open(attr, pid, cpu, flags)?],
+        };
+        Ok(Self { config, files })
+    }
+
+    /// Each count is read along with the times needed to scale it if the PMU was multiplexed.
+    const READ_FORMAT: u64 =
+        PERF_FORMAT_TOTAL_TIME_ENABLED as u64 | PERF_FORMAT_TOTAL_TIME_RUNNING as u64;
+
     pub fn enable(&self) -> Result<()> {
-        self.file.enable()
+        for file in &self.files {
+            file.enable()?;
+        }
+        Ok(())
     }
 
+    /// Reads the count, scaled up to estimate it over the whole time the counter was enabled if
+    /// the PMU was multiplexed between more events than it could count at once.
     pub fn read(&mut self) -> Result<(Counted, u64)> {
-        let mut value: u64 = 0;
-
-        // NOTE(unsafe): we're just generating a pointer to a stack variable,
-        // not saving that pointer beyond this stack frame
-        let mut value_slice = unsafe {
-            let ptr = (&mut value as *mut u64) as *mut u8;
-            let len = size_of::<u64>();
-            slice::from_raw_parts_mut(ptr, len)
-        };
+        let (count, _) = self.read_scaled()?;
+        Ok((self.config.event, count))
+    }
 
-        self.file.read(&mut value_slice)?;
+    /// Reads the scaled count along with the percentage of the time the counter was enabled
+    /// which it actually spent counting.
+    pub(crate) fn read_scaled(&mut self) -> Result<(u64, f64)> {
+        let mut readings = Vec::with_capacity(self.files.len());
+        for file in &mut self.files {
+            // { u64 value; u64 time_enabled; u64 time_running; }
+            let mut bytes = [0u8; 3 * size_of::<u64>()];
+            file.read_exact(&mut bytes)?;
+            let read = ReadValues::from_bytes(&bytes, Self::READ_FORMAT)?;
+            readings.push((
+                read.values[0].value,
+                read.time_enabled.unwrap_or(0),
+                read.time_running.unwrap_or(0),
+            ));
+        }
+        Ok(scale_readings(&readings))
+    }
 
-        Ok((self.config.event.clone(), value))
+    /// The file descriptor of each file the counter reads from: one, or one per online CPU for a
+    /// cgroup counter opened with `CpuConfig::All`.
+    pub fn raw_fds(&self) -> Vec<RawFd> {
+        self.files.iter().map(AsRawFd::as_raw_fd).collect()
     }
+
+    /// Fields of the event's attr which the kernel didn't support, and which were dropped to
+    /// open it.
+    pub(crate) fn dropped(&self) -> Vec<AttrExtension> {
+        let mut dropped = self
+            .files
+            .iter()
+            .flat_map(|file| file.dropped().iter().cloned())
+            .collect::<Vec<_>>();
+        dropped.sort();
+        dropped.dedup();
+        dropped
+    }
+}
+
+/// Sums (value, time_enabled, time_running) readings, each scaled by how long it was enabled over
+/// how long it was running, like perf-stat(1). Returns the total and the percentage of the
+/// enabled time which was spent running, which is 100 if the counter was never enabled.
+pub(crate) fn scale_readings(readings: &[(u64, u64, u64)]) -> (u64, f64) {
+    let (mut total, mut enabled, mut running) = (0u64, 0u64, 0u64);
+    for &(value, time_enabled, time_running) in readings {
+        total += if time_running < time_enabled {
+            PerfClock::scale(value, time_enabled, time_running)
+        } else {
+            value
+        };
+        enabled += time_enabled;
+        running += time_running;
+    }
+
+    let percent = if running < enabled {
+        running as f64 * 100.0 / enabled as f64
+    } else {
+        100.0
+    };
+    (total, percent)
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Counted {
     Hardware(HwEvent),
@@ -102,6 +190,16 @@ impl PerfEventAttrThingy for Counted {
 }
 
 impl Counted {
+    /// The event's serialized name, e.g. "cpu-cycles" or "l1d-read-miss", which is the same as
+    /// perf-list(1)'s for most events.
+    pub fn name(&self) -> String {
+        match *self {
+            Counted::Hardware(hw_event) => hw_event.str().to_owned(),
+            Counted::Software(sw_event) => sw_event.str().to_owned(),
+            Counted::HardwareCache(spec) => spec.str(),
+        }
+    }
+
     pub(crate) fn all() -> Vec<Self> {
         let mut variants = Vec::new();
 
@@ -145,7 +243,9 @@ impl Display for Counted {
 }
 
 #[repr(u64)]
-#[derive(Clone, Copy, Debug, Display, EnumIter, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(
+    Clone, Copy, Debug, Display, EnumIter, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub enum SwEvent {
     /// This reports the CPU clock, a high-resolution per-CPU timer.
     #[serde(rename = "cpu-clock")]
@@ -215,8 +315,28 @@ pub enum SwEvent {
     DummyForSampled = PERF_COUNT_SW_DUMMY as u64,
 }
 
+impl SwEvent {
+    /// The name it's serialized with.
+    fn str(&self) -> &'static str {
+        match *self {
+            SwEvent::CpuClock => "cpu-clock",
+            SwEvent::TaskClock => "task-clock",
+            SwEvent::ContextSwitches => "context-switches",
+            SwEvent::CpuMigrations => "cpu-migrations",
+            SwEvent::PageFaults => "page-fault",
+            SwEvent::PageFaultsMinor => "page-fault-minor",
+            SwEvent::PageFaultsMajor => "page-faults-major",
+            SwEvent::AlignmentFaults => "align-faults",
+            SwEvent::EmulationFaults => "emulation-faults",
+            SwEvent::DummyForSampled => "dummy",
+        }
+    }
+}
+
 #[repr(u64)]
-#[derive(Clone, Copy, Debug, Display, EnumIter, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(
+    Clone, Copy, Debug, Display, EnumIter, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub enum HwEvent {
     /// Total cycles. Be wary of what happens during CPU frequency scaling.
     #[serde(rename = "cpu-cycles")]
@@ -274,20 +394,75 @@ pub enum HwEvent {
     RefCpuCycles = PERF_COUNT_HW_REF_CPU_CYCLES as u64,
 }
 
+impl HwEvent {
+    /// The name it's serialized with.
+    fn str(&self) -> &'static str {
+        match *self {
+            HwEvent::CpuCycles => "cpu-cycles",
+            HwEvent::Instructions => "instructions",
+            HwEvent::CacheReferences => "cache-references",
+            HwEvent::CacheMisses => "cache-misses",
+            HwEvent::BranchInstructions => "branch-instructions",
+            HwEvent::BranchMisses => "branch-misses",
+            HwEvent::BusCycles => "bus-cycles",
+            HwEvent::StalledCyclesFrontend => "stalled-cycles-frontend",
+            HwEvent::StalledCyclesBackend => "stalled-cycles-backend",
+            HwEvent::RefCpuCycles => "ref-cpu-cycles",
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
-pub struct HardwareCacheSpec(CacheId, CacheOpId, CacheOpResultId);
+pub struct HardwareCacheSpec(pub CacheId, pub CacheOpId, pub CacheOpResultId);
+
+impl HardwareCacheSpec {
+    /// The name it's serialized with, e.g. "l1d-read-miss".
+    fn str(&self) -> String {
+        format!("{}-{}-{}", self.0.str(), self.1.str(), self.2.str())
+    }
+}
 
 impl Serialize for HardwareCacheSpec {
     fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.collect_str(&format_args!(
-            "{}-{}-{}",
-            self.0.str(),
-            self.1.str(),
-            self.2.str()
-        ))
+        serializer.serialize_str(&self.str())
+    }
+}
+
+impl<'de> Deserialize<'de> for HardwareCacheSpec {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Parses the serialized form, e.g. "l1d-read-miss" or "dtlb-write-access".
+impl FromStr for HardwareCacheSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(3, '-');
+        let mut next = || parts.next().unwrap_or("");
+        let (id, op, result) = (next(), next(), next());
+
+        let spec = CacheId::iter().find(|c| c.str() == id).and_then(|id| {
+            let op = CacheOpId::iter().find(|o| o.str() == op)?;
+            let result = CacheOpResultId::iter().find(|r| r.str() == result)?;
+            Some(HardwareCacheSpec(id, op, result))
+        });
+
+        spec.ok_or_else(|| Error::Misc {
+            inner: format!(
+                "unknown cache event {:?}, expected <cache>-<op>-<result> like \"l1d-read-miss\"",
+                s
+            ),
+        })
     }
 }
 
@@ -1,32 +1,222 @@
-use failure;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
+use std::path::PathBuf;
+
+use libc::pid_t;
 use nix;
 
+use capabilities::Hint;
+use count::Counted;
 use fd::{FileControlError, OpenError};
-use sample::ring_buffer::BufferError;
+use raw::perf_event_attr;
+use sample::record::DecodeError;
+use sample::ring_buffer::{BufferError, BufferSizeError};
+use spec::SpecError;
+use {CpuConfig, PidConfig};
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-#[derive(Debug, Fail)]
+/// Errors from opening, controlling and reading events. Each variant's message describes what
+/// failed, and the underlying error, if any, is its `source()`.
+#[derive(Debug, Error)]
 pub enum Error {
-    #[fail(display = "Failed to open a perf_events file descriptor: {}", inner)]
-    FdOpen { inner: OpenError },
-    #[fail(display = "Failed to start collecting metrics: {}", inner)]
-    Start { inner: String },
-    #[fail(display = "Failed to interact with a POSIX API: {}", inner)]
-    Posix { inner: nix::Error },
-    #[fail(display = "Failed to read from a perf_events file descriptor: {}", inner)]
-    Read { inner: ::std::io::Error },
-    #[fail(display = "Failed to mmap a perf_events file descriptor: {}", inner)]
-    Mmap { inner: BufferError },
-    #[fail(display = "Failed to call fcntl on a perf_events file descriptor: {}", inner)]
-    Fcntl { inner: FileControlError },
-    #[fail(display = "Encountered an unknown error: {}", inner)]
-    Misc { inner: failure::Error },
+    #[error(
+        "failed to open event type {} config {:#x} for {pid} on {cpu}: {inner}",
+        .attr.type_,
+        .attr.config
+    )]
+    FdOpen {
+        inner: OpenError,
+        /// The attr which was passed to perf_event_open(2).
+        attr: Box<perf_event_attr>,
+        pid: PidConfig,
+        cpu: CpuConfig,
+    },
+    #[error("failed to start collecting metrics: {failures}")]
+    Start { failures: Failures },
+    #[error("{call} failed")]
+    Posix {
+        /// The system call or ioctl, e.g. "PERF_EVENT_IOC_ENABLE".
+        call: &'static str,
+        #[source]
+        inner: nix::Error,
+    },
+    #[error("failed to read from a perf_events file descriptor")]
+    Read {
+        #[source]
+        inner: io::Error,
+    },
+    #[error("failed to mmap a ring buffer")]
+    Mmap {
+        #[source]
+        inner: BufferError,
+    },
+    #[error("invalid ring buffer size")]
+    BufferSize {
+        #[source]
+        inner: BufferSizeError,
+    },
+    #[error("fcntl failed on a perf_events file descriptor")]
+    Fcntl {
+        #[source]
+        inner: FileControlError,
+    },
+    #[error("failed to decode a record from a ring buffer")]
+    Decode {
+        #[source]
+        inner: DecodeError,
+    },
+    #[error("failed to spawn or wait for a command")]
+    Spawn {
+        #[source]
+        inner: io::Error,
+    },
+    #[error("no process or thread {pid}")]
+    NoSuchProcess { pid: pid_t },
+    #[error("failed to read {}", .path.display())]
+    Proc {
+        path: PathBuf,
+        #[source]
+        inner: io::Error,
+    },
+    #[error("failed to open cgroup {}", .path.display())]
+    Cgroup {
+        path: PathBuf,
+        #[source]
+        inner: io::Error,
+    },
+    #[error("failed to load session spec {}", .path.display())]
+    Spec {
+        path: PathBuf,
+        #[source]
+        inner: SpecError,
+    },
+    #[error("{inner}")]
+    Misc { inner: String },
+}
+
+impl Error {
+    /// Wraps an error from perf_event_open(2) with what was being opened.
+    pub(crate) fn open(
+        inner: OpenError,
+        attr: &perf_event_attr,
+        pid: &PidConfig,
+        cpu: CpuConfig,
+    ) -> Self {
+        Error::FdOpen {
+            inner,
+            attr: Box::new(*attr),
+            pid: pid.clone(),
+            cpu,
+        }
+    }
+
+    /// Suggests how to avoid an error from perf_event_open(2) if the system's configuration is
+    /// the likely cause. This reads the system's configuration each time it's called, and may
+    /// open test events, so it isn't part of the error's message.
+    pub fn hint(&self) -> Hint {
+        match *self {
+            Error::FdOpen {
+                ref inner,
+                ref attr,
+                ref pid,
+                ..
+            } => Hint::for_open(inner, attr, pid),
+            _ => Hint(None),
+        }
+    }
+
+    /// Broadly why an event couldn't be opened, e.g. to group failures in a report.
+    pub fn kind(&self) -> FailureKind {
+        match *self {
+            Error::FdOpen { ref inner, .. } => match *inner {
+                OpenError::AttrWrongSize
+                | OpenError::InvalidEvent
+                | OpenError::CpuFeatureUnsupported
+                | OpenError::InvalidEventType
+                | OpenError::UserStackSampleUnsupported
+                | OpenError::HardwareFeatureUnsupported
+                | OpenError::SampleMaxStackTooLarge => FailureKind::Unsupported,
+                OpenError::CapSysAdminRequired
+                | OpenError::CapSysAdminRequiredOrExcludeUnsupported => FailureKind::Permission,
+                OpenError::PmuBusy | OpenError::TooManyBreakpoints => FailureKind::PmuBusy,
+                OpenError::TooManyOpenFiles => FailureKind::FdLimit,
+                _ => FailureKind::Other,
+            },
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// Broad reasons for an event not opening, in the order a report lists them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize)]
+pub enum FailureKind {
+    /// The kernel, CPU or PMU doesn't support the event or one of its settings. Declaring a
+    /// `Fallback` may help.
+    Unsupported,
+    /// The process isn't allowed to measure the event. `Capabilities` has the details.
+    Permission,
+    /// The PMU's counters or breakpoint slots are all in use, e.g. by the NMI watchdog or
+    /// another profiler.
+    PmuBusy,
+    /// The process ran out of file descriptors, since each event needs one.
+    FdLimit,
+    /// Anything else, e.g. a missing process or cgroup.
+    Other,
+}
+
+impl Display for FailureKind {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(match *self {
+            FailureKind::Unsupported => "unsupported",
+            FailureKind::Permission => "permission denied",
+            FailureKind::PmuBusy => "PMU busy",
+            FailureKind::FdLimit => "out of file descriptors",
+            FailureKind::Other => "other errors",
+        })
+    }
+}
+
+/// The events which couldn't be opened or enabled, and why.
+#[derive(Debug, Default)]
+pub struct Failures(pub BTreeMap<Counted, Error>);
+
+impl Failures {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The failed events, grouped by why they failed.
+    pub fn by_kind(&self) -> BTreeMap<FailureKind, Vec<Counted>> {
+        let mut grouped = BTreeMap::<_, Vec<_>>::new();
+        for (event, error) in &self.0 {
+            grouped.entry(error.kind()).or_default().push(*event);
+        }
+        grouped
+    }
 }
 
-impl From<nix::Error> for Error {
-    fn from(inner: nix::Error) -> Self {
-        Error::Posix { inner }
+/// Lists the events grouped by kind, e.g. "unsupported: Hardware: cpu-cycles; permission denied:
+/// ...", since the errors' own messages are too long to repeat for every event.
+impl Display for Failures {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.is_empty() {
+            return f.write_str("no events were requested");
+        }
+        for (i, (kind, events)) in self.by_kind().into_iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}: ", kind)?;
+            for (j, event) in events.iter().enumerate() {
+                if j > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", event)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -36,21 +226,21 @@ impl From<BufferError> for Error {
     }
 }
 
-impl From<FileControlError> for Error {
-    fn from(inner: FileControlError) -> Self {
-        Error::Fcntl { inner }
+impl From<BufferSizeError> for Error {
+    fn from(inner: BufferSizeError) -> Self {
+        Error::BufferSize { inner }
     }
 }
 
-impl From<failure::Error> for Error {
-    fn from(inner: failure::Error) -> Self {
-        Error::Misc { inner }
+impl From<FileControlError> for Error {
+    fn from(inner: FileControlError) -> Self {
+        Error::Fcntl { inner }
     }
 }
 
-impl From<OpenError> for Error {
-    fn from(inner: OpenError) -> Self {
-        Error::FdOpen { inner }
+impl From<DecodeError> for Error {
+    fn from(inner: DecodeError) -> Self {
+        Error::Decode { inner }
     }
 }
 
@@ -59,3 +249,52 @@ impl From<::std::io::Error> for Error {
         Error::Read { inner }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn open_errors_name_the_event_and_target() {
+        let attr = perf_event_attr {
+            type_: 4,
+            config: 0x1c2,
+            ..Default::default()
+        };
+
+        let e = Error::open(
+            OpenError::InvalidFdOrPid,
+            &attr,
+            &PidConfig::Other(42),
+            CpuConfig::Specific(3),
+        );
+        assert_eq!(
+            e.to_string(),
+            "failed to open event type 4 config 0x1c2 for pid 42 on CPU 3: \
+             EBADF: invalid group or cgroup file descriptor"
+        );
+        match e {
+            Error::FdOpen { ref inner, .. } => assert_eq!(inner.errno(), nix::errno::Errno::EBADF),
+            _ => unreachable!(),
+        }
+        assert_eq!(e.hint(), Hint(None));
+
+        let e = Error::open(
+            OpenError::TooManyOpenFiles,
+            &attr,
+            &PidConfig::Current,
+            CpuConfig::All,
+        );
+        let hint = e.hint().0.unwrap();
+        assert!(hint.contains("RLIMIT_NOFILE"));
+        assert!(!e.to_string().contains("hint"));
+
+        let e = Error::Posix {
+            call: "PERF_EVENT_IOC_ENABLE",
+            inner: nix::Error::Sys(nix::errno::Errno::EBADF),
+        };
+        assert_eq!(e.to_string(), "PERF_EVENT_IOC_ENABLE failed");
+        assert!(e.source().is_some());
+    }
+}
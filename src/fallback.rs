@@ -0,0 +1,200 @@
+use capabilities::Capabilities;
+use count::Counted;
+use error::*;
+use fd::OpenError;
+
+/// A way to retry opening an event which the kernel or hardware rejected, tried in the order
+/// they're declared.
+///
+/// Errors that the event is invalid or unsupported (EINVAL, ENODEV, ENOENT and EOPNOTSUPP) are
+/// first retried at a lower `precise_ip` if `LowerPrecision` was declared, and then with the next
+/// `Event`. Permission errors (EACCES and EPERM) are first retried with `ExcludeKernel` if it was
+/// declared and applies, and then with the next `Event`. Other errors aren't retried.
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Fallback {
+    /// Opens this event instead, with the rest of the config unchanged, e.g.
+    /// `Counted::Software(SwEvent::CpuClock)` in place of `HwEvent::CpuCycles` on a machine
+    /// without a PMU.
+    Event(Counted),
+    /// Lowers a sampled event's `precise_ip` one level at a time until the hardware accepts it or
+    /// it reaches 0, allowing more skid. Most virtual machines don't support precise sampling.
+    LowerPrecision,
+    /// Sets `exclude_kernel` and `exclude_hv` if perf_event_paranoid is 2 or more and the process
+    /// doesn't have CAP_PERFMON or CAP_SYS_ADMIN.
+    ExcludeKernel,
+}
+
+/// What was actually opened for an event which declared fallbacks.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Opened {
+    /// The event which was asked for.
+    pub requested: Counted,
+    /// The event which was opened, which differs from `requested` if a `Fallback::Event` was used.
+    pub event: Counted,
+    /// The precise_ip the event was opened with. Always 0 for counters.
+    pub precise_ip: u16,
+    pub exclude_kernel: bool,
+    pub exclude_hv: bool,
+    /// The fallbacks which were applied, in order. A `LowerPrecision` appears once per level.
+    pub fallbacks: Vec<Fallback>,
+}
+
+impl Opened {
+    pub(crate) fn new(
+        event: Counted,
+        precise_ip: u16,
+        exclude_kernel: bool,
+        exclude_hv: bool,
+    ) -> Self {
+        Opened {
+            requested: event,
+            event,
+            precise_ip,
+            exclude_kernel,
+            exclude_hv,
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// Whether anything other than what was requested was opened.
+    pub fn fell_back(&self) -> bool {
+        !self.fallbacks.is_empty()
+    }
+
+    /// Tries `open` with the requested settings and then with each applicable fallback, returning
+    /// what `open` returned for the first attempt which succeeded. If none do, the error from the
+    /// requested settings is returned.
+    pub(crate) fn with_fallbacks<T>(
+        mut self,
+        fallbacks: &[Fallback],
+        mut open: impl FnMut(&Opened) -> Result<T>,
+    ) -> Result<(T, Opened)> {
+        let mut events = fallbacks.iter().filter_map(|fallback| match *fallback {
+            Fallback::Event(event) => Some(event),
+            _ => None,
+        });
+        let declared = |wanted: Fallback| fallbacks.contains(&wanted);
+        let mut first_error = None;
+
+        loop {
+            let why = match open(&self) {
+                Ok(opened) => {
+                    if self.fell_back() {
+                        info!("opened {:?} after falling back", self);
+                    }
+                    return Ok((opened, self));
+                }
+                Err(why) => why,
+            };
+
+            let (unsupported, denied) = match why {
+                Error::FdOpen { ref inner, .. } => (
+                    matches!(
+                        *inner,
+                        OpenError::InvalidEvent
+                            | OpenError::CpuFeatureUnsupported
+                            | OpenError::InvalidEventType
+                            | OpenError::HardwareFeatureUnsupported
+                    ),
+                    matches!(
+                        *inner,
+                        OpenError::CapSysAdminRequired
+                            | OpenError::CapSysAdminRequiredOrExcludeUnsupported
+                    ),
+                ),
+                _ => (false, false),
+            };
+            debug!("unable to open {:?}: {}", self, why);
+
+            let applied =
+                if unsupported && declared(Fallback::LowerPrecision) && self.precise_ip > 0 {
+                    self.precise_ip -= 1;
+                    Some(Fallback::LowerPrecision)
+                } else if denied
+                    && declared(Fallback::ExcludeKernel)
+                    && !self.exclude_kernel
+                    && kernel_profiling_denied()
+                {
+                    self.exclude_kernel = true;
+                    self.exclude_hv = true;
+                    Some(Fallback::ExcludeKernel)
+                } else if unsupported || denied {
+                    events.next().map(|event| {
+                        self.event = event;
+                        Fallback::Event(event)
+                    })
+                } else {
+                    None
+                };
+
+            match applied {
+                Some(applied) => {
+                    first_error.get_or_insert(why);
+                    self.fallbacks.push(applied);
+                }
+                None => return Err(first_error.unwrap_or(why)),
+            }
+        }
+    }
+}
+
+fn kernel_profiling_denied() -> bool {
+    let capabilities = Capabilities::read_system();
+    !capabilities.privileged() && capabilities.paranoid.is_some_and(|level| level >= 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raw::perf_event_attr;
+    use {CpuConfig, HwEvent, PidConfig, SwEvent};
+
+    fn fail(inner: OpenError) -> Error {
+        Error::open(
+            inner,
+            &perf_event_attr::default(),
+            &PidConfig::Current,
+            CpuConfig::All,
+        )
+    }
+
+    #[test]
+    fn falls_back_in_order() {
+        let cycles = Counted::Hardware(HwEvent::CpuCycles);
+        let clock = Counted::Software(SwEvent::CpuClock);
+        let fallbacks = [Fallback::LowerPrecision, Fallback::Event(clock)];
+
+        let requested = Opened::new(cycles, 2, false, false);
+        let ((), opened) = requested
+            .with_fallbacks(&fallbacks, |attempt| match attempt.event {
+                Counted::Hardware(_) => Err(fail(OpenError::HardwareFeatureUnsupported)),
+                _ => Ok(()),
+            })
+            .unwrap();
+        assert_eq!(opened.event, clock);
+        assert_eq!(opened.precise_ip, 0);
+        assert_eq!(
+            opened.fallbacks,
+            vec![
+                Fallback::LowerPrecision,
+                Fallback::LowerPrecision,
+                Fallback::Event(clock)
+            ]
+        );
+
+        // other errors, or running out of fallbacks, returns the first error
+        let requested = Opened::new(cycles, 0, false, false);
+        let res: Result<((), _)> =
+            requested.with_fallbacks(&fallbacks, |attempt| match attempt.event {
+                Counted::Hardware(_) => Err(fail(OpenError::InvalidEventType)),
+                _ => Err(fail(OpenError::TooManyOpenFiles)),
+            });
+        match res {
+            Err(Error::FdOpen {
+                inner: OpenError::InvalidEventType,
+                ..
+            }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -4,68 +4,329 @@ use std::io;
 use std::io::Error as IoError;
 use std::io::Read;
 use std::io::Result as IoResult;
-use std::ops::{Deref, DerefMut};
+use std::mem::{size_of, size_of_val};
+use std::ops::{Deref, DerefMut, Range};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use libc::*;
 use mio::{unix::EventedFd, Evented, Poll, PollOpt, Ready, Token};
+use enum_primitive::FromPrimitive;
 use nix::errno::errno;
 use nix::errno::Errno;
 
 use super::{CpuConfig, PidConfig};
 use error::*;
-use raw::perf_event_attr;
+use raw::{
+    perf_event_attr, PERF_ATTR_SIZE_VER0, PERF_ATTR_SIZE_VER1, PERF_ATTR_SIZE_VER2,
+    PERF_ATTR_SIZE_VER3, PERF_ATTR_SIZE_VER4, PERF_ATTR_SIZE_VER5, PERF_ATTR_SIZE_VER6,
+    PERF_FLAG_FD_CLOEXEC, PERF_FLAG_FD_NO_GROUP, PERF_FLAG_FD_OUTPUT, PERF_FLAG_PID_CGROUP,
+};
 
 pub trait PerfEventAttrThingy {
     fn apply(&self, &mut perf_event_attr);
 }
 
+/// An open event, and any fields of its attr which the kernel didn't know about and which were
+/// dropped to open it.
 #[derive(Debug)]
-pub struct PerfFile(pub(crate) File);
+pub struct PerfFile(pub(crate) File, Vec<AttrExtension>);
+
+/// The size of perf_event_attr the running kernel accepts, once an E2BIG has shown that it's
+/// smaller than ours. Zero until then.
+static KERNEL_ATTR_SIZE: AtomicU32 = AtomicU32::new(0);
+
+/// A perf_event_attr field which follows the original 64 byte struct. A kernel which predates a
+/// field rejects an attr which sets it with E2BIG, and `PerfFile::open` retries without it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+pub enum AttrExtension {
+    /// bp_len or config2 (PERF_ATTR_SIZE_VER1, since Linux 2.6.39)
+    Config2,
+    /// branch_sample_type (PERF_ATTR_SIZE_VER2, since Linux 3.4)
+    BranchSampleType,
+    /// sample_regs_user (PERF_ATTR_SIZE_VER3, since Linux 3.7)
+    SampleRegsUser,
+    /// sample_stack_user (PERF_ATTR_SIZE_VER3, since Linux 3.7)
+    SampleStackUser,
+    /// clockid, which shares PERF_ATTR_SIZE_VER3's padding (since Linux 4.1)
+    Clockid,
+    /// sample_regs_intr (PERF_ATTR_SIZE_VER4, since Linux 3.19)
+    SampleRegsIntr,
+    /// aux_watermark (PERF_ATTR_SIZE_VER5, since Linux 4.1)
+    AuxWatermark,
+    /// sample_max_stack (PERF_ATTR_SIZE_VER5's padding, since Linux 4.8)
+    SampleMaxStack,
+    /// aux_sample_size (PERF_ATTR_SIZE_VER6, since Linux 5.5)
+    AuxSampleSize,
+}
+
+impl AttrExtension {
+    fn all() -> Vec<Self> {
+        use self::AttrExtension::*;
+        vec![
+            Config2,
+            BranchSampleType,
+            SampleRegsUser,
+            SampleStackUser,
+            Clockid,
+            SampleRegsIntr,
+            AuxWatermark,
+            SampleMaxStack,
+            AuxSampleSize,
+        ]
+    }
+
+    /// The field's bytes within `attr`.
+    fn range(&self, attr: &perf_event_attr) -> Range<usize> {
+        let (start, len) = match *self {
+            AttrExtension::Config2 => {
+                let field = &attr.__bindgen_anon_4;
+                (field as *const _ as usize, size_of_val(field))
+            }
+            AttrExtension::BranchSampleType => field_bytes(&attr.branch_sample_type),
+            AttrExtension::SampleRegsUser => field_bytes(&attr.sample_regs_user),
+            AttrExtension::SampleStackUser => field_bytes(&attr.sample_stack_user),
+            AttrExtension::Clockid => field_bytes(&attr.clockid),
+            AttrExtension::SampleRegsIntr => field_bytes(&attr.sample_regs_intr),
+            AttrExtension::AuxWatermark => field_bytes(&attr.aux_watermark),
+            AttrExtension::SampleMaxStack => field_bytes(&attr.sample_max_stack),
+            AttrExtension::AuxSampleSize => field_bytes(&attr.aux_sample_size),
+        };
+        let offset = start - (attr as *const _ as usize);
+        offset..offset + len
+    }
+}
+
+fn field_bytes<T>(field: &T) -> (usize, usize) {
+    (field as *const T as usize, size_of::<T>())
+}
+
+/// Shrinks `attr` to `size` bytes for a kernel which doesn't know about the rest, returning the
+/// fields which were set and are now dropped.
+fn truncate_attr(attr: &mut perf_event_attr, size: u32) -> Vec<AttrExtension> {
+    let dropped = {
+        // NOTE(unsafe) the attr is plain data, and the slice doesn't outlive the borrow
+        let bytes = unsafe {
+            slice::from_raw_parts(attr as *const _ as *const u8, size_of::<perf_event_attr>())
+        };
+        AttrExtension::all()
+            .into_iter()
+            .filter(|field| {
+                let range = field.range(attr);
+                range.end > size as usize && bytes[range].iter().any(|&b| b != 0)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    if dropped.contains(&AttrExtension::Clockid) {
+        attr.set_use_clockid(0);
+    }
+
+    // NOTE(unsafe) zeroing is valid for every field, and the range is within the struct
+    unsafe {
+        let start = (attr as *mut _ as *mut u8).add(size as usize);
+        ::std::ptr::write_bytes(start, 0, size_of::<perf_event_attr>() - size as usize);
+    }
+    attr.size = size;
+    dropped
+}
+
+bitflags! {
+    /// The flags argument of perf_event_open(2).
+    pub struct OpenFlags: u32 {
+        /// Enables the close-on-exec flag for the created event file descriptor, so that it's
+        /// closed on execve(2) rather than leaked into child processes. (since Linux 3.14)
+        const FD_CLOEXEC = PERF_FLAG_FD_CLOEXEC;
+
+        /// Tells the event to ignore the group_fd parameter except for the purpose of setting up
+        /// output redirection using the FD_OUTPUT flag. Only `Topdown` opens events in a group,
+        /// and opening any other event with this flag is an error.
+        const FD_NO_GROUP = PERF_FLAG_FD_NO_GROUP;
+
+        /// Re-routes the event's sampled output to instead be included in the mmap buffer of the
+        /// event specified by group_fd. This has been broken since Linux 2.6.35, and the ring
+        /// buffers use PERF_EVENT_IOC_SET_OUTPUT instead. Opening an event which isn't in a group
+        /// with this flag is an error.
+        const FD_OUTPUT = PERF_FLAG_FD_OUTPUT;
+
+        /// Activates per-container system-wide monitoring, where the pid is a file descriptor
+        /// for a cgroup directory. This is set automatically for `PidConfig::Cgroup`, and setting
+        /// it for any other `PidConfig` is an error. (since Linux 2.6.39)
+        const PID_CGROUP = PERF_FLAG_PID_CGROUP;
+    }
+}
+
+serde_flag_names!(OpenFlags {
+    FD_CLOEXEC,
+    FD_NO_GROUP,
+    FD_OUTPUT,
+    PID_CGROUP,
+});
+
+impl Default for OpenFlags {
+    fn default() -> Self {
+        OpenFlags::FD_CLOEXEC
+    }
+}
 
 impl PerfFile {
-    pub fn new(
-        config: impl Debug + Into<perf_event_attr> + AsRef<PidConfig> + AsRef<CpuConfig>,
+    /// Opens an event from an already-built attr.
+    pub fn open(
+        attr: &perf_event_attr,
+        pid: &PidConfig,
+        cpu: CpuConfig,
+        flags: OpenFlags,
     ) -> Result<Self> {
-        // pub(crate) fn as_raw(&self, disabled: bool) -> perf_event_attr {
-        // NOTE(unsafe) a zeroed struct is what the example c code uses,
-        // zero fields are interpreted as "off" afaict, aside from the required fields
-        let pid: PidConfig = *config.as_ref();
-        let cpu: CpuConfig = *config.as_ref();
-        let attr = config.into();
-
-        // NOTE(unsafe) it'd be a kernel bug if this caused unsafety, i think
-        unsafe {
-            let res = syscall(
-                SYS_perf_event_open,
-                &attr,
-                pid.raw(),
-                cpu.raw(),
-                // ignore group_fd, since we can't set inherit *and* read multiple from a group
-                -1,
-                // NOTE: doesnt seem like this is needed for this library, but
-                // i could be wrong. CLOEXEC doesn't seem to apply when we won't
-                // leak the file descriptor, NO_GROUP doesn't make since FD_OUTPUT
-                // has been broken since 2.6.35, and PID_CGROUP isn't useful
-                // unless you're running inside containers, which i don't need to
-                // support yet
-                0,
-            );
+        Self::open_in_group(attr, pid, cpu, flags, None)
+    }
 
-            if res == -1 {
-                let e = Error::from(OpenError::from(Errno::last()));
-                debug!("unable to open {:?}: {:?}", attr, e);
-                Err(e)
-            } else {
+    /// Opens an event as a member of `leader`'s group, or as a group leader if `None`. The kernel
+    /// only schedules a group onto the PMU all at once, so its events count over the same time.
+    /// Members must be opened for the same task and CPU as their leader.
+    pub fn open_in_group(
+        attr: &perf_event_attr,
+        pid: &PidConfig,
+        cpu: CpuConfig,
+        flags: OpenFlags,
+        leader: Option<&PerfFile>,
+    ) -> Result<Self> {
+        let misplaced = if leader.is_none() {
+            flags & (OpenFlags::FD_NO_GROUP | OpenFlags::FD_OUTPUT | OpenFlags::PID_CGROUP)
+        } else {
+            flags & OpenFlags::PID_CGROUP
+        };
+        if !misplaced.is_empty() {
+            return Err(Error::Misc {
+                inner: format!(
+                    "{:?} can't be set here: FD_NO_GROUP and FD_OUTPUT only apply to events \
+                     opened in a group, and PID_CGROUP is set for PidConfig::Cgroup",
+                    misplaced
+                ),
+            });
+        }
+
+        // the kernel takes a cgroup as an fd for its directory in place of the pid, which only
+        // needs to stay open for the call
+        let (raw_pid, flags, _cgroup) = match *pid {
+            PidConfig::Current => (0, flags, None),
+            PidConfig::Other(pid) => (pid, flags, None),
+            PidConfig::Cgroup(ref path) => {
+                if cpu == CpuConfig::All {
+                    return Err(Error::Misc {
+                        inner: "cgroup events must be opened for a single CPU".into(),
+                    });
+                }
+                let dir = File::open(path).map_err(|inner| Error::Cgroup {
+                    path: path.clone(),
+                    inner,
+                })?;
+                (dir.as_raw_fd(), flags | OpenFlags::PID_CGROUP, Some(dir))
+            }
+        };
+
+        let group_fd = leader.map_or(-1, |leader| leader.as_raw_fd());
+        Self::open_raw(attr, raw_pid, cpu, group_fd, flags).map_err(|inner| {
+            let e = Error::open(inner, attr, pid, cpu);
+            debug!("unable to open {:?}: {}", attr, e);
+            e
+        })
+    }
+
+    /// Makes the syscall with a pid or cgroup fd and a group leader's fd or -1, without looking
+    /// for hints on failure.
+    ///
+    /// If the kernel's perf_event_attr is smaller than ours and the attr sets fields past its
+    /// end, the kernel fails with E2BIG and writes its size back to the attr. This retries with
+    /// that size, or each older PERF_ATTR_SIZE_VER* if it doesn't look right, and remembers it for
+    /// later events.
+    pub(crate) fn open_raw(
+        attr: &perf_event_attr,
+        pid: pid_t,
+        cpu: CpuConfig,
+        group_fd: c_int,
+        flags: OpenFlags,
+    ) -> ::std::result::Result<Self, OpenError> {
+        let mut attr = *attr;
+        let mut dropped = Vec::new();
+
+        let known = KERNEL_ATTR_SIZE.load(Ordering::Relaxed);
+        if known != 0 && known < attr.size {
+            dropped = truncate_attr(&mut attr, known);
+        }
+
+        loop {
+            let requested = attr.size;
+
+            // NOTE(unsafe) it'd be a kernel bug if this caused unsafety, i think
+            let res = unsafe {
+                syscall(
+                    SYS_perf_event_open,
+                    &mut attr as *mut perf_event_attr,
+                    pid,
+                    cpu.raw(),
+                    // -1 for everything except Topdown's members: counters and samplers are
+                    // opened alone so they can set inherit, which the kernel doesn't allow for
+                    // events which read their whole group
+                    group_fd,
+                    c_ulong::from(flags.bits()),
+                )
+            };
+
+            if res != -1 {
+                if !dropped.is_empty() {
+                    warn!(
+                        "the kernel's perf_event_attr is {} bytes, so {:?} were dropped",
+                        attr.size, dropped
+                    );
+                }
                 // NOTE(unsafe) if the kernel doesn't give -1, guarantees the fd is valid
-                let f = File::from_raw_fd(res as i32);
-                Ok(PerfFile(f))
+                let f = unsafe { File::from_raw_fd(res as i32) };
+                return Ok(PerfFile(f, dropped));
             }
+
+            let errno = Errno::last();
+            if errno != Errno::E2BIG {
+                return Err(OpenError::from(errno));
+            }
+
+            // only a smaller size than the one which was rejected is worth retrying with
+            let reported = attr.size;
+            let size = if reported >= PERF_ATTR_SIZE_VER0 && reported < requested {
+                reported
+            } else {
+                match [
+                    PERF_ATTR_SIZE_VER6,
+                    PERF_ATTR_SIZE_VER5,
+                    PERF_ATTR_SIZE_VER4,
+                    PERF_ATTR_SIZE_VER3,
+                    PERF_ATTR_SIZE_VER2,
+                    PERF_ATTR_SIZE_VER1,
+                    PERF_ATTR_SIZE_VER0,
+                ]
+                .iter()
+                .find(|&&size| size < requested)
+                {
+                    Some(&size) => size,
+                    None => return Err(OpenError::AttrWrongSize),
+                }
+            };
+
+            debug!(
+                "perf_event_attr size {} is too big, retrying with {}",
+                requested, size
+            );
+            KERNEL_ATTR_SIZE.store(size, Ordering::Relaxed);
+            dropped.extend(truncate_attr(&mut attr, size));
         }
     }
 
+    /// Fields of the attr which the kernel didn't support, and which were dropped to open it.
+    pub(crate) fn dropped(&self) -> &[AttrExtension] {
+        &self.1
+    }
+
     pub fn enable(&self) -> Result<()> {
-        const PERF_EVENT_IOC_MAGIC: u8 = b'$';
         const PERF_EVENT_IOC_ENABLE_MODE: u8 = 0;
 
         ioctl!(
@@ -81,12 +342,186 @@ impl PerfFile {
                 .map(|_| ())
                 .map_err(|e| {
                     warn!("Unable to enable a pe file descriptor: {:?}", e);
-                    Error::Posix { inner: e }
+                    Error::Posix {
+                        call: "PERF_EVENT_IOC_ENABLE",
+                        inner: e,
+                    }
+                })
+        }
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        const PERF_EVENT_IOC_DISABLE_MODE: u8 = 1;
+
+        ioctl!(
+            none
+            perf_event_ioc_disable
+            with
+            PERF_EVENT_IOC_MAGIC,
+            PERF_EVENT_IOC_DISABLE_MODE
+        );
+
+        unsafe {
+            perf_event_ioc_disable(self.0.as_raw_fd())
+                .map(|_| ())
+                .map_err(|e| {
+                    warn!("Unable to disable a pe file descriptor: {:?}", e);
+                    Error::Posix {
+                        call: "PERF_EVENT_IOC_DISABLE",
+                        inner: e,
+                    }
+                })
+        }
+    }
+
+    /// Enables the event for `overflows` more overflows, after which it's disabled and a
+    /// notification with POLL_HUP is sent. Non-inherited overflow counters can use this to enable
+    /// a counter for a number of overflows specified by the argument. A value of 0 is undefined.
+    pub fn refresh(&self, overflows: c_int) -> Result<()> {
+        const PERF_EVENT_IOC_REFRESH_MODE: u8 = 2;
+
+        ioctl!(
+            bad write_int
+            perf_event_ioc_refresh
+            with
+            io!(PERF_EVENT_IOC_MAGIC, PERF_EVENT_IOC_REFRESH_MODE)
+        );
+
+        unsafe {
+            perf_event_ioc_refresh(self.0.as_raw_fd(), overflows)
+                .map(|_| ())
+                .map_err(|e| {
+                    warn!("Unable to refresh a pe file descriptor: {:?}", e);
+                    Error::Posix {
+                        call: "PERF_EVENT_IOC_REFRESH",
+                        inner: e,
+                    }
+                })
+        }
+    }
+
+    /// Asks the kernel to send `signal` to the thread `tid` whenever this event notifies its
+    /// readers, i.e. on ring buffer wakeups and when an overflow limit set with `refresh` is
+    /// reached. The signal's siginfo_t has si_fd set to this file descriptor.
+    pub fn signal_thread(&self, tid: pid_t, signal: c_int) -> Result<()> {
+        let fd = self.0.as_raw_fd();
+
+        let fcntl_error = || -> Error {
+            let errno = errno();
+            match FileControlError::from_i32(errno) {
+                Some(e) => e.into(),
+                None => Error::Posix {
+                    call: "fcntl",
+                    inner: ::nix::Error::Sys(Errno::from_i32(errno)),
+                },
+            }
+        };
+
+        // NOTE(unsafe) only the fd's flags and owner are changed
+        unsafe {
+            let flags = fcntl(fd, F_GETFL);
+            if flags == -1 || fcntl(fd, F_SETFL, flags | O_ASYNC) == -1 {
+                return Err(fcntl_error());
+            }
+
+            if fcntl(fd, F_SETSIG, signal) == -1 {
+                return Err(fcntl_error());
+            }
+
+            let owner = f_owner_ex {
+                type_: F_OWNER_TID,
+                pid: tid,
+            };
+            if fcntl(fd, F_SETOWN_EX, &owner as *const f_owner_ex) == -1 {
+                return Err(fcntl_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the kernel's unique identifier for this event, the same value which is reported in
+    /// PERF_SAMPLE_ID and PERF_SAMPLE_IDENTIFIER.
+    pub fn id(&self) -> Result<u64> {
+        const PERF_EVENT_IOC_ID_MODE: u8 = 7;
+
+        ioctl!(
+            bad read
+            perf_event_ioc_id
+            with
+            ior!(PERF_EVENT_IOC_MAGIC, PERF_EVENT_IOC_ID_MODE, size_of::<*mut u64>());
+            u64
+        );
+
+        let mut id = 0;
+        unsafe {
+            perf_event_ioc_id(self.0.as_raw_fd(), &mut id)
+                .map(|_| id)
+                .map_err(|e| {
+                    warn!("Unable to get the id of a pe file descriptor: {:?}", e);
+                    Error::Posix {
+                        call: "PERF_EVENT_IOC_ID",
+                        inner: e,
+                    }
+                })
+        }
+    }
+
+    /// Sends this event's records to the ring buffer mapped for `target` instead of requiring a
+    /// buffer of its own. Both events must be on the same CPU (or both follow the same task) and
+    /// this event must not already have been mmap'd.
+    pub fn set_output(&self, target: &PerfFile) -> Result<()> {
+        const PERF_EVENT_IOC_SET_OUTPUT_MODE: u8 = 5;
+
+        ioctl!(
+            bad write_int
+            perf_event_ioc_set_output
+            with
+            io!(PERF_EVENT_IOC_MAGIC, PERF_EVENT_IOC_SET_OUTPUT_MODE)
+        );
+
+        unsafe {
+            perf_event_ioc_set_output(self.0.as_raw_fd(), target.as_raw_fd())
+                .map(|_| ())
+                .map_err(|e| {
+                    warn!("Unable to redirect a pe file descriptor's output: {:?}", e);
+                    Error::Posix {
+                        call: "PERF_EVENT_IOC_SET_OUTPUT",
+                        inner: e,
+                    }
+                })
+        }
+    }
+
+    /// Stops (or resumes) the kernel writing to this event's ring buffer. Records which would have
+    /// been written while paused are dropped. (since Linux 4.7)
+    pub fn pause_output(&self, paused: bool) -> Result<()> {
+        const PERF_EVENT_IOC_PAUSE_OUTPUT_MODE: u8 = 9;
+
+        // the kernel reads the argument's value, not through a pointer
+        ioctl!(
+            bad write_int
+            perf_event_ioc_pause_output
+            with
+            iow!(PERF_EVENT_IOC_MAGIC, PERF_EVENT_IOC_PAUSE_OUTPUT_MODE, size_of::<u32>())
+        );
+
+        unsafe {
+            perf_event_ioc_pause_output(self.0.as_raw_fd(), paused as c_int)
+                .map(|_| ())
+                .map_err(|e| {
+                    warn!("Unable to pause a pe file descriptor's output: {:?}", e);
+                    Error::Posix {
+                        call: "PERF_EVENT_IOC_PAUSE_OUTPUT",
+                        inner: e,
+                    }
                 })
         }
     }
 }
 
+const PERF_EVENT_IOC_MAGIC: u8 = b'$';
+
 impl Evented for PerfFile {
     fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> IoResult<()> {
         info!("registering {:?}", self.0);
@@ -110,117 +545,84 @@ impl Evented for PerfFile {
     }
 }
 
-#[derive(Debug, Fail)]
+#[derive(Debug, Error)]
 pub enum OpenError {
-    #[fail(
-        display = "Returned if the perf_event_attr size value is too small
-              (smaller than PERF_ATTR_SIZE_VER0), too big (larger than the
-              page size), or larger than the kernel supports and the extra
-              bytes are not zero.  When E2BIG is returned, the
-              perf_event_attr size field is overwritten by the kernel to be
-              the size of the structure it was expecting."
-    )]
+    /// Returned if the perf_event_attr size value is too small (smaller than PERF_ATTR_SIZE_VER0),
+    /// too big (larger than the page size), or larger than the kernel supports and the extra bytes
+    /// are not zero. When E2BIG is returned, the perf_event_attr size field is overwritten by the
+    /// kernel to be the size of the structure it was expecting.
+    #[error("E2BIG: perf_event_attr's size is wrong for this kernel")]
     AttrWrongSize,
-    #[fail(
-        display = "Returned when the requested event requires CAP_SYS_ADMIN
-              permissions (or a more permissive perf_event paranoid
-              setting).  Some common cases where an unprivileged process may
-              encounter this error: attaching to a process owned by a
-              different user; monitoring all processes on a given CPU (i.e.,
-              specifying the pid argument as -1); and not setting
-              exclude_kernel when the paranoid setting requires it."
-    )]
+    /// Returned when the requested event requires CAP_SYS_ADMIN permissions (or a more permissive
+    /// perf_event paranoid setting). Some common cases where an unprivileged process may encounter
+    /// this error: attaching to a process owned by a different user; monitoring all processes on a
+    /// given CPU (i.e., specifying the pid argument as -1); and not setting exclude_kernel when the
+    /// paranoid setting requires it.
+    #[error("EACCES: permission denied")]
     CapSysAdminRequired,
-    #[fail(
-        display = "Returned if the group_fd file descriptor is not valid, or, if
-              PERF_FLAG_PID_CGROUP is set, the cgroup file descriptor in pid
-              is not valid."
-    )]
+    /// Returned if the group_fd file descriptor is not valid, or, if PERF_FLAG_PID_CGROUP is set,
+    /// the cgroup file descriptor in pid is not valid.
+    #[error("EBADF: invalid group or cgroup file descriptor")]
     InvalidFdOrPid,
-    #[fail(
-        display = "Returned if another event already has exclusive access to the
-              PMU."
-    )]
+    /// Returned if another event already has exclusive access to the PMU.
+    #[error("EBUSY: another event has exclusive access to the PMU")]
     PmuBusy,
-    #[fail(
-        display = "Returned if the attr pointer points at an invalid memory
-              address."
-    )]
+    /// Returned if the attr pointer points at an invalid memory address.
+    #[error("EFAULT: invalid attr pointer")]
     AttrInvalidPointer,
-    #[fail(
-        display = "Returned if the specified event is invalid.  There are many
-              possible reasons for this.  A not-exhaustive list: sample_freq
-              is higher than the maximum setting; the cpu to monitor does
-              not exist; read_format is out of range; sample_type is out of
-              range; the flags value is out of range; exclusive or pinned
-              set and the event is not a group leader; the event config
-              values are out of range or set reserved bits; the generic
-              event selected is not supported; or there is not enough room
-              to add the selected event."
-    )]
+    /// Returned if the specified event is invalid. There are many possible reasons for this. A
+    /// not-exhaustive list: sample_freq is higher than the maximum setting; the cpu to monitor does
+    /// not exist; read_format is out of range; sample_type is out of range; the flags value is out
+    /// of range; exclusive or pinned set and the event is not a group leader; the event config
+    /// values are out of range or set reserved bits; the generic event selected is not supported;
+    /// or there is not enough room to add the selected event.
+    #[error("EINVAL: invalid event")]
     InvalidEvent,
-    #[fail(
-        display = "Each opened event uses one file descriptor.  If a large number
-              of events are opened, the per-process limit on the number of
-              open file descriptors will be reached, and no more events can
-              be created."
-    )]
+    /// Each opened event uses one file descriptor. If a large number of events are opened, the
+    /// per-process limit on the number of open file descriptors will be reached, and no more events
+    /// can be created.
+    #[error("EMFILE: too many open files")]
     TooManyOpenFiles,
-    #[fail(
-        display = "Returned when the event involves a feature not supported by
-              the current CPU."
-    )]
+    /// Returned when the event involves a feature not supported by the current CPU.
+    #[error("ENODEV: not supported by this CPU")]
     CpuFeatureUnsupported,
-    #[fail(
-        display = "Returned if the type setting is not valid.  This error is also
-              returned for some unsupported generic events."
-    )]
+    /// Returned if the type setting is not valid. This error is also returned for some unsupported
+    /// generic events.
+    #[error("ENOENT: invalid or unsupported event type")]
     InvalidEventType,
-    #[fail(
-        display = "Prior to Linux 3.3, if there was not enough room for the
-              event, ENOSPC was returned.  In Linux 3.3, this was changed to
-              EINVAL.  ENOSPC is still returned if you try to add more
-              breakpoint events than supported by the hardware."
-    )]
+    /// Prior to Linux 3.3, if there was not enough room for the event, ENOSPC was returned. In
+    /// Linux 3.3, this was changed to EINVAL. ENOSPC is still returned if you try to add more
+    /// breakpoint events than supported by the hardware.
+    #[error("ENOSPC: no room for more breakpoints")]
     TooManyBreakpoints,
-    #[fail(
-        display = "Returned if PERF_SAMPLE_STACK_USER is set in sample_type and
-              it is not supported by hardware."
-    )]
+    /// Returned if PERF_SAMPLE_STACK_USER is set in sample_type and it is not supported by
+    /// hardware.
+    #[error("ENOSYS: user stack sampling isn't supported")]
     UserStackSampleUnsupported,
-    #[fail(
-        display = "Returned if an event requiring a specific hardware feature is
-              requested but there is no hardware support.  This includes
-              requesting low-skid events if not supported, branch tracing if
-              it is not available, sampling if no PMU interrupt is
-              available, and branch stacks for software events."
-    )]
+    /// Returned if an event requiring a specific hardware feature is requested but there is no
+    /// hardware support. This includes requesting low-skid events if not supported, branch tracing
+    /// if it is not available, sampling if no PMU interrupt is available, and branch stacks for
+    /// software events.
+    #[error("EOPNOTSUPP: needs a hardware feature which isn't available")]
     HardwareFeatureUnsupported,
-    #[fail(
-        display = "(since Linux 4.8)
-              Returned if PERF_SAMPLE_CALLCHAIN is requested and
-              sample_max_stack is larger than the maximum specified in
-              /proc/sys/kernel/perf_event_max_stack."
-    )]
+    /// (since Linux 4.8) Returned if PERF_SAMPLE_CALLCHAIN is requested and sample_max_stack is
+    /// larger than the maximum specified in /proc/sys/kernel/perf_event_max_stack.
+    #[error("EOVERFLOW: sample_max_stack is above perf_event_max_stack")]
     SampleMaxStackTooLarge,
-    #[fail(
-        display = "Returned on many (but not all) architectures when an
-              unsupported exclude_hv, exclude_idle, exclude_user, or
-              exclude_kernel setting is specified.
-
-              It can also happen, as with EACCES, when the requested event
-              requires CAP_SYS_ADMIN permissions (or a more permissive
-              perf_event paranoid setting).  This includes setting a
-              breakpoint on a kernel address, and (since Linux 3.13) setting
-              a kernel function-trace tracepoint."
-    )]
+    /// Returned on many (but not all) architectures when an unsupported exclude_hv, exclude_idle,
+    /// exclude_user, or exclude_kernel setting is specified.
+    ///
+    /// It can also happen, as with EACCES, when the requested event requires CAP_SYS_ADMIN
+    /// permissions (or a more permissive perf_event paranoid setting). This includes setting a
+    /// breakpoint on a kernel address, and (since Linux 3.13) setting a kernel function-trace
+    /// tracepoint.
+    #[error("EPERM: not permitted, or an exclude setting is unsupported")]
     CapSysAdminRequiredOrExcludeUnsupported,
-    #[fail(
-        display = "Returned if attempting to attach to a process that does not
-              exist."
-    )]
+    /// Returned if attempting to attach to a process that does not exist.
+    #[error("ESRCH: no such process")]
     ProcessDoesNotExist,
-    #[fail(display = "The kernel returned an unexpected error code: {}", errno)]
+    /// Any other errno, which perf_event_open(2) doesn't document.
+    #[error("{errno}: unexpected error")]
     Unknown { errno: Errno },
 }
 
@@ -247,6 +649,30 @@ impl From<Errno> for OpenError {
     }
 }
 
+impl OpenError {
+    /// The errno perf_event_open(2) returned.
+    pub fn errno(&self) -> Errno {
+        match *self {
+            OpenError::AttrWrongSize => Errno::E2BIG,
+            OpenError::CapSysAdminRequired => Errno::EACCES,
+            OpenError::InvalidFdOrPid => Errno::EBADF,
+            OpenError::PmuBusy => Errno::EBUSY,
+            OpenError::AttrInvalidPointer => Errno::EFAULT,
+            OpenError::InvalidEvent => Errno::EINVAL,
+            OpenError::TooManyOpenFiles => Errno::EMFILE,
+            OpenError::CpuFeatureUnsupported => Errno::ENODEV,
+            OpenError::InvalidEventType => Errno::ENOENT,
+            OpenError::TooManyBreakpoints => Errno::ENOSPC,
+            OpenError::UserStackSampleUnsupported => Errno::ENOSYS,
+            OpenError::HardwareFeatureUnsupported => Errno::EOPNOTSUPP,
+            OpenError::SampleMaxStackTooLarge => Errno::EOVERFLOW,
+            OpenError::CapSysAdminRequiredOrExcludeUnsupported => Errno::EPERM,
+            OpenError::ProcessDoesNotExist => Errno::ESRCH,
+            OpenError::Unknown { errno } => errno,
+        }
+    }
+}
+
 impl Read for PerfFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.0.read(buf)
@@ -274,112 +700,110 @@ impl DerefMut for PerfFile {
 
 enum_from_primitive! {
 #[repr(i32)]
-#[derive(Debug, Fail)]
+#[derive(Debug, Error)]
 pub enum FileControlError {
-    #[fail(display = "Operation is prohibited by locks held by other processes.")]
+    /// Operation is prohibited by locks held by other processes.
+    #[error("EACCES: prohibited by locks held by other processes")]
     Prohibited = EACCES,
 
-    #[fail(
-        display = "The operation is prohibited because the file has been memory-mapped by another
-        process."
-    )]
+    /// The operation is prohibited because the file has been memory-mapped by another process.
+    #[error("EAGAIN: memory-mapped by another process")]
     MappedByAnother = EAGAIN,
 
-    #[fail(
-        display = "fd is not an open file descriptor.
-
-        -or-
-
-        cmd is F_SETLK or F_SETLKW and the file descriptor open mode doesn't match with the type of
-        lock requested."
-    )]
+    /// fd is not an open file descriptor.
+    ///
+    /// -or-
+    ///
+    /// cmd is F_SETLK or F_SETLKW and the file descriptor open mode doesn't match with the type of
+    /// lock requested.
+    #[error("EBADF: bad file descriptor")]
     BadFd = EBADF,
 
-    #[fail(
-        display = "cmd is F_SETPIPE_SZ and the new pipe capacity specified in arg is smaller than
-        the amount of buffer space currently used to store data in the pipe.
-
-        -or-
-
-        cmd is F_ADD_SEALS, arg includes F_SEAL_WRITE, and there exists a writable, shared mapping
-        on the file referred to by fd."
-    )]
+    /// cmd is F_SETPIPE_SZ and the new pipe capacity specified in arg is smaller than the amount of
+    /// buffer space currently used to store data in the pipe.
+    ///
+    /// -or-
+    ///
+    /// cmd is F_ADD_SEALS, arg includes F_SEAL_WRITE, and there exists a writable, shared mapping
+    /// on the file referred to by fd.
+    #[error("EBUSY: busy")]
     Busy = EBUSY,
 
-    #[fail(
-        display = "It was detected that the specified F_SETLKW command would cause a deadlock."
-    )]
+    /// It was detected that the specified F_SETLKW command would cause a deadlock.
+    #[error("EDEADLK: would deadlock")]
     WouldDeadlock = EDEADLK,
 
-    #[fail(display = "lock is outside your accessible address space.")]
+    /// lock is outside your accessible address space.
+    #[error("EFAULT: lock is outside the accessible address space")]
     Unaddressable = EFAULT,
 
-    #[fail(
-        display = "cmd is F_SETLKW or F_OFD_SETLKW and the operation was interrupted by a signal;
-        see signal(7).
-        -or-
-        cmd is F_GETLK, F_SETLK, F_OFD_GETLK, or F_OFD_SETLK, and the operation was interrupted by a
-        signal before the lock was checked or acquired. Most likely when locking a remote file
-        (e.g., locking over NFS), but can sometimes happen locally."
-    )]
+    /// cmd is F_SETLKW or F_OFD_SETLKW and the operation was interrupted by a signal; see
+    /// signal(7).
+    ///
+    /// -or-
+    ///
+    /// cmd is F_GETLK, F_SETLK, F_OFD_GETLK, or F_OFD_SETLK, and the operation was interrupted by a
+    /// signal before the lock was checked or acquired. Most likely when locking a remote file
+    /// (e.g., locking over NFS), but can sometimes happen locally.
+    #[error("EINTR: interrupted by a signal")]
     Interrupted = EINTR,
 
-    #[fail(
-        display = "The value specified in cmd is not recognized by this kernel.
-
-        -or-
-
-        cmd is F_ADD_SEALS and arg includes an unrecognized sealing bit.
-
-        -or-
-
-        cmd is F_ADD_SEALS or F_GET_SEALS and the filesystem containing the inode referred to by fd
-        does not support sealing.
-
-        -or-
-
-        cmd is F_DUPFD and arg is negative or is greater than the maximum allowable value (see the
-        discussion of RLIMIT_NOFILE in getrlimit(2)).
-
-        -or-
-
-        cmd is F_SETSIG and arg is not an allowable signal number.
-
-        -or-
-
-        cmd is F_OFD_SETLK, F_OFD_SETLKW, or F_OFD_GETLK, and l_pid was not specified as zero."
-    )]
+    /// The value specified in cmd is not recognized by this kernel.
+    ///
+    /// -or-
+    ///
+    /// cmd is F_ADD_SEALS and arg includes an unrecognized sealing bit.
+    ///
+    /// -or-
+    ///
+    /// cmd is F_ADD_SEALS or F_GET_SEALS and the filesystem containing the inode referred to by fd
+    /// does not support sealing.
+    ///
+    /// -or-
+    ///
+    /// cmd is F_DUPFD and arg is negative or is greater than the maximum allowable value (see the
+    /// discussion of RLIMIT_NOFILE in getrlimit(2)).
+    ///
+    /// -or-
+    ///
+    /// cmd is F_SETSIG and arg is not an allowable signal number.
+    ///
+    /// -or-
+    ///
+    /// cmd is F_OFD_SETLK, F_OFD_SETLKW, or F_OFD_GETLK, and l_pid was not specified as zero.
+    #[error("EINVAL: invalid argument")]
     InsertCowboyBebopReferenceHereBecauseItsEinval = EINVAL,
 
-    #[fail(
-        display = "cmd is F_DUPFD and the per-process limit on the number of open file descriptors
-        has been reached."
-    )]
+    /// cmd is F_DUPFD and the per-process limit on the number of open file descriptors has been
+    /// reached.
+    #[error("EMFILE: too many open files")]
     TooManyOpenFiles = EMFILE,
 
-    #[fail(
-        display = "Too many segment locks open, lock table is full, or a remote locking protocol
-        failed (e.g., locking over NFS)."
-    )]
+    /// Too many segment locks open, lock table is full, or a remote locking protocol failed (e.g.,
+    /// locking over NFS).
+    #[error("ENOLCK: locking failed")]
     LockingFailed = ENOLCK,
 
-    #[fail(display = "F_NOTIFY was specified in cmd, but fd does not refer to a directory.")]
+    /// F_NOTIFY was specified in cmd, but fd does not refer to a directory.
+    #[error("ENOTDIR: not a directory")]
     NotADirectory = ENOTDIR,
 
-    #[fail(
-        display = "cmd is F_SETPIPE_SZ and the soft or hard user pipe limit has been reached; see
-        pipe(7).
-
-        -or-
-
-        Attempted to clear the O_APPEND flag on a file that has the append-only attribute set.
-
-        -or-
-
-        cmd was F_ADD_SEALS, but fd was not open for writing or the current set of seals on the file
-        already includes F_SEAL_SEAL."
-    )]
+    /// cmd is F_SETPIPE_SZ and the soft or hard user pipe limit has been reached; see pipe(7).
+    ///
+    /// -or-
+    ///
+    /// Attempted to clear the O_APPEND flag on a file that has the append-only attribute set.
+    ///
+    /// -or-
+    ///
+    /// cmd was F_ADD_SEALS, but fd was not open for writing or the current set of seals on the file
+    /// already includes F_SEAL_SEAL.
+    #[error("EPERM: operation not permitted")]
     SeveralMiscellaneousErrors = EPERM,
+
+    /// cmd is F_SETOWN_EX and the owner doesn't refer to an existing thread.
+    #[error("ESRCH: the owner thread doesn't exist")]
+    NoSuchOwner = ESRCH,
 }
 }
 
@@ -390,3 +814,66 @@ const F_SETSIG: i32 = 10;
 const F_OWNER_TID: c_int = 0;
 // #define F_SETOWN_EX 15
 const F_SETOWN_EX: c_int = 15;
+
+// struct f_owner_ex {
+// 	int	type;
+// 	__kernel_pid_t	pid;
+// };
+#[repr(C)]
+struct f_owner_ex {
+    type_: c_int,
+    pid: pid_t,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use count::Counted;
+    use {EventConfig, PidConfig, SwEvent};
+
+    fn task_clock() -> perf_event_attr {
+        let mut attr = EventConfig::default().raw();
+        Counted::Software(SwEvent::TaskClock).apply(&mut attr);
+        attr
+    }
+
+    #[test]
+    fn truncates_attr_for_older_kernels() {
+        let mut attr = task_clock();
+        attr.branch_sample_type = 1;
+        attr.set_use_clockid(1);
+        attr.clockid = CLOCK_MONOTONIC;
+        attr.aux_sample_size = 4096;
+
+        let dropped = truncate_attr(&mut attr, PERF_ATTR_SIZE_VER3 - 8);
+        assert_eq!(
+            dropped,
+            vec![AttrExtension::Clockid, AttrExtension::AuxSampleSize]
+        );
+        assert_eq!(attr.size, PERF_ATTR_SIZE_VER3 - 8);
+        assert_eq!((attr.clockid, attr.aux_sample_size), (0, 0));
+        assert_eq!(attr.use_clockid(), 0);
+        assert_eq!(attr.branch_sample_type, 1);
+
+        // the kernel accepts the original struct's size from a newer binary
+        let mut attr = task_clock();
+        truncate_attr(&mut attr, PERF_ATTR_SIZE_VER0);
+        let file = PerfFile::open_raw(&attr, 0, CpuConfig::All, -1, OpenFlags::default()).unwrap();
+        assert!(file.dropped().is_empty());
+    }
+
+    #[test]
+    fn rejects_misplaced_flags() {
+        let attr = task_clock();
+        let open = |flags, leader| {
+            PerfFile::open_in_group(&attr, &PidConfig::Current, CpuConfig::All, flags, leader)
+        };
+
+        let leader = open(OpenFlags::default(), None).unwrap();
+        assert!(open(OpenFlags::PID_CGROUP, None).is_err());
+        assert!(open(OpenFlags::PID_CGROUP, Some(&leader)).is_err());
+        assert!(open(OpenFlags::FD_NO_GROUP, None).is_err());
+        assert!(open(OpenFlags::FD_OUTPUT, None).is_err());
+        assert!(open(OpenFlags::FD_NO_GROUP, Some(&leader)).is_ok());
+    }
+}
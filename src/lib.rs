@@ -1,14 +1,9 @@
-// TODO The official way of knowing if perf_event_open() support is enabled
-//    is checking for the existence of the file
-//    /proc/sys/kernel/perf_event_paranoid.
-
 #[macro_use]
 extern crate bitflags;
 #[macro_use]
-extern crate enum_primitive;
-extern crate failure;
+extern crate crossbeam_channel as channel;
 #[macro_use]
-extern crate failure_derive;
+extern crate enum_primitive;
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -17,9 +12,10 @@ extern crate nix;
 extern crate serde_derive;
 #[macro_use]
 extern crate strum_macros;
+#[macro_use]
+extern crate thiserror;
 
 extern crate bytes;
-extern crate crossbeam_channel as channel;
 extern crate futures;
 extern crate libc;
 extern crate mio;
@@ -27,8 +23,15 @@ extern crate mmap;
 extern crate num;
 extern crate page_size;
 extern crate serde;
+extern crate serde_json;
 extern crate strum;
 extern crate tokio;
+extern crate toml;
+
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(any(test, feature = "async"))]
+extern crate tokio1;
 
 #[cfg(test)]
 extern crate env_logger;
@@ -38,75 +41,211 @@ extern crate pretty_assertions;
 #[cfg(test)]
 extern crate rand;
 
+/// Implements `Serialize` and `Deserialize` for a bitflags struct as a list of its flags' names,
+/// e.g. `["FD_CLOEXEC"]`, so that config files don't need to spell out raw bits. A flag which is
+/// the union of earlier ones is only listed if they weren't.
+macro_rules! serde_flag_names {
+    ($flags:ident { $($flag:ident),* $(,)* }) => {
+        impl ::serde::Serialize for $flags {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+            where
+                S: ::serde::Serializer,
+            {
+                let mut covered = $flags::empty();
+                let mut names = Vec::new();
+                $(
+                    if self.contains($flags::$flag) && !covered.contains($flags::$flag) {
+                        covered |= $flags::$flag;
+                        names.push(stringify!($flag));
+                    }
+                )*
+                serializer.collect_seq(names)
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $flags {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+            where
+                D: ::serde::Deserializer<'de>,
+            {
+                const NAMES: &[&str] = &[$(stringify!($flag)),*];
+                let mut flags = $flags::empty();
+                for name in Vec::<String>::deserialize(deserializer)? {
+                    flags |= match &*name {
+                        $(stringify!($flag) => $flags::$flag,)*
+                        other => return Err(::serde::de::Error::unknown_variant(other, NAMES)),
+                    };
+                }
+                Ok(flags)
+            }
+        }
+    };
+}
+
+pub(crate) mod capabilities;
+pub(crate) mod clock;
+pub(crate) mod command;
 pub(crate) mod count;
 pub mod error;
+pub(crate) mod fallback;
 pub(crate) mod fd;
+pub(crate) mod pmu;
+pub(crate) mod process;
 pub(crate) mod raw;
+pub(crate) mod report;
 pub mod sample;
+pub(crate) mod self_monitor;
+pub(crate) mod spec;
+pub(crate) mod tma;
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use libc::pid_t;
 
-use count::{CountConfig, Counted, Counter};
+use count::{CountConfig, Counter};
+pub use capabilities::{AttrField, Capabilities, Hint, KernelVersion};
+pub use clock::{Clock, ClockCorrelation};
+pub use command::{count_command, sample_command, CommandCounts, CommandSamples};
+pub use count::{CacheId, CacheOpId, CacheOpResultId, Counted, HardwareCacheSpec, HwEvent, SwEvent};
 pub use error::*;
+pub use fallback::{Fallback, Opened};
+pub use fd::{AttrExtension, OpenFlags};
+pub use process::{online_cpus, process_threads, ProcessCounter, ThreadCount, ThreadCounter};
+pub use report::{CountReport, Metrics};
+pub use self_monitor::{PerfClock, SelfMonitoredCounter};
+pub use spec::{CountSpec, SessionSpec, SpecError};
+pub use tma::{Level1, Level2, Topdown, TopdownMethod, TopdownReport};
 
+#[derive(Debug)]
 pub struct Perf {
     counters: Vec<Counter>,
+    opened: Vec<Opened>,
+    /// When `start` was last called.
+    started: Option<Instant>,
 }
 
 impl Perf {
     pub fn new(config: EventConfig) -> PerfBuilder {
         PerfBuilder {
             config,
-            to_count: BTreeSet::new(),
+            to_count: BTreeMap::new(),
         }
     }
 
-    pub fn start(&mut self) -> Vec<Result<()>> {
-        self.counters.iter().map(|c| c.enable()).collect()
+    /// Enables every counter, returning which couldn't be enabled, if any. The others are enabled
+    /// either way.
+    pub fn start(&mut self) -> Result<()> {
+        self.started = Some(Instant::now());
+        let mut failures = BTreeMap::new();
+        for (counter, opened) in self.counters.iter().zip(&self.opened) {
+            if let Err(why) = counter.enable() {
+                failures.insert(opened.requested, why);
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Start {
+                failures: Failures(failures),
+            })
+        }
     }
 
+    /// Reads each counter, keyed by the event which was requested for it. `opened` says which
+    /// event was counted in its place if it fell back to another. Counts of events which were
+    /// multiplexed are scaled up to estimate them over the whole time they were enabled.
     pub fn read(&mut self) -> BTreeMap<Counted, u64> {
+        self.read_scaled()
+            .into_iter()
+            .map(|(event, (count, _))| (event, count))
+            .collect()
+    }
+
+    /// Reads the counts along with the metrics derived from them, e.g. instructions per cycle,
+    /// over the time since `start` was last called.
+    pub fn report(&mut self) -> CountReport {
+        let elapsed = self.started.map_or(Duration::from_secs(0), |s| s.elapsed());
+        let scaled = self.read_scaled();
+        let running_percent = scaled
+            .iter()
+            .filter(|(_, &(_, percent))| percent < 100.0)
+            .map(|(&event, &(_, percent))| (event, percent))
+            .collect();
+        let counts = scaled
+            .into_iter()
+            .map(|(event, (count, _))| (event, count))
+            .collect();
+
+        CountReport {
+            running_percent,
+            ..CountReport::new(counts, &self.opened, elapsed)
+        }
+    }
+
+    /// Reads each counter's scaled count and the percentage of its enabled time it was running.
+    fn read_scaled(&mut self) -> BTreeMap<Counted, (u64, f64)> {
         self.counters
             .iter_mut()
-            .filter_map(|c| {
-                let res = c.read();
+            .zip(&self.opened)
+            .filter_map(|(c, opened)| {
+                let res = c.read_scaled();
                 if let Err(ref why) = res {
                     debug!("error reading counter: {}", why);
                 }
-                res.ok()
+                res.ok().map(|scaled| (opened.requested, scaled))
             })
             .collect()
     }
 
+    /// Returns what was opened for each event, after any of their fallbacks.
+    pub fn opened(&self) -> &[Opened] {
+        &self.opened
+    }
+
+    /// Returns the fields of the shared config which this kernel doesn't support, and which were
+    /// dropped to open each requested event, for the events which had any.
+    pub fn dropped_fields(&self) -> BTreeMap<Counted, Vec<AttrExtension>> {
+        self.counters
+            .iter()
+            .zip(&self.opened)
+            .map(|(c, opened)| (opened.requested, c.dropped()))
+            .filter(|(_, dropped)| !dropped.is_empty())
+            .collect()
+    }
+
+    /// Opens and starts every counter this machine supports, returning an error listing why if
+    /// none of them could be opened. Counters which can't be enabled are logged and kept, and
+    /// count nothing.
     pub fn start_all_counts_available() -> Result<Self> {
-        let res = Perf::new(EventConfig::default())
+        let report = Perf::new(EventConfig::default())
             .all_counts_available()
             .create();
 
-        if let (_, Err(ref failures)) = res {
-            for (event, error) in failures {
-                trace!("error creating event {:?}: {}", event, error);
-            }
+        for (event, error) in &report.failures.0 {
+            trace!("error creating event {:?}: {}", event, error);
         }
 
-        if let (Ok(mut counts), _) = res {
-            counts.start();
-            Ok(counts)
-        } else {
-            // TODO return error explaining that no counters were available
-            Err(Error::Start {
-                inner: String::from("No counters started successfully."),
-            })
+        let mut counts = report.into_perf()?;
+        match counts.start() {
+            Err(Error::Start { failures }) => {
+                for (event, error) in &failures.0 {
+                    warn!("error enabling event {:?}: {}", event, error);
+                }
+            }
+            res => res?,
         }
+        Ok(counts)
     }
 }
 
 #[derive(Debug)]
 pub struct PerfBuilder {
     config: EventConfig,
-    to_count: BTreeSet<Counted>,
+    to_count: BTreeMap<Counted, Vec<Fallback>>,
 }
 
 impl PerfBuilder {
@@ -119,49 +258,92 @@ impl PerfBuilder {
     }
 
     pub fn count(mut self, event: Counted) -> Self {
-        self.to_count.insert(event);
+        self.to_count.entry(event).or_default();
+        self
+    }
+
+    /// Counts `event`, or if it can't be opened, the first of `fallbacks` which can, e.g.
+    /// `HwEvent::CpuCycles` falling back to `SwEvent::CpuClock` and then `SwEvent::TaskClock`.
+    pub fn count_with_fallbacks(mut self, event: Counted, fallbacks: Vec<Fallback>) -> Self {
+        self.to_count.insert(event, fallbacks);
         self
     }
 
-    pub fn create(
-        self,
-    ) -> (
-        ::std::result::Result<Perf, ()>,
-        ::std::result::Result<(), BTreeMap<Counted, Error>>,
-    ) {
+    /// Opens a counter for each event, reporting why any couldn't be opened.
+    pub fn create(self) -> CreateReport {
         let mut counters = Vec::new();
+        let mut opened = Vec::new();
         let mut failures = BTreeMap::new();
 
-        for event in self.to_count {
-            let config = CountConfig {
-                shared: self.config.clone(),
-                event: event.clone(),
-            };
-            match Counter::new(config) {
-                Ok(c) => counters.push(c),
+        for (event, fallbacks) in self.to_count {
+            let shared = &self.config;
+            let requested = Opened::new(event, 0, shared.exclude_kernel, shared.exclude_hv);
+            let res = requested.with_fallbacks(&fallbacks, |attempt| {
+                Counter::new(CountConfig {
+                    shared: EventConfig {
+                        exclude_kernel: attempt.exclude_kernel,
+                        exclude_hv: attempt.exclude_hv,
+                        ..shared.clone()
+                    },
+                    event: attempt.event,
+                })
+            });
+            match res {
+                Ok((c, o)) => {
+                    counters.push(c);
+                    opened.push(o);
+                }
                 Err(why) => {
                     failures.insert(event, why);
                 }
             };
         }
 
-        let ret_counts = if counters.len() == 0 {
-            Err(())
+        let perf = if counters.is_empty() {
+            None
         } else {
-            Ok(Perf { counters })
+            Some(Perf {
+                counters,
+                opened,
+                started: None,
+            })
         };
 
-        let ret_failures = if failures.len() == 0 {
-            Ok(())
-        } else {
-            Err(failures)
-        };
+        CreateReport {
+            perf,
+            failures: Failures(failures),
+        }
+    }
+}
+
+/// The counters which `PerfBuilder::create` opened, and why the other events couldn't be.
+#[derive(Debug)]
+pub struct CreateReport {
+    /// The opened counters, or `None` if no events could be opened.
+    pub perf: Option<Perf>,
+    pub failures: Failures,
+}
 
-        (ret_counts, ret_failures)
+impl CreateReport {
+    /// Whether every event was opened.
+    pub fn is_complete(&self) -> bool {
+        self.perf.is_some() && self.failures.is_empty()
+    }
+
+    /// Returns the counters which were opened, even if some events couldn't be, or an error
+    /// listing why none could be.
+    pub fn into_perf(self) -> Result<Perf> {
+        match self.perf {
+            Some(perf) => Ok(perf),
+            None => Err(Error::Start {
+                failures: self.failures,
+            }),
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EventConfig {
     pub pid: PidConfig,
     pub cpu: CpuConfig,
@@ -206,23 +388,28 @@ pub struct EventConfig {
     /// clockid field. This can make it easier to correlate perf sample times with timestamps
     /// generated by other tools.
     ///
-    /// If set, then this field selects which internal Linux timer to use for
-    /// timestamps. The available timers are defined in linux/time.h, with CLOCK_MONOTONIC,
-    /// CLOCK_MONOTONIC_RAW, CLOCK_REALTIME, CLOCK_BOOTTIME, and CLOCK_TAI currently supported.
+    /// If set, then this field selects which internal Linux timer to use for timestamps, otherwise
+    /// the kernel's perf_clock is used. Either way, `ClockCorrelation` converts the timestamps to
+    /// `SystemTime`s and `Instant`s.
     ///
     /// (since Linux 4.1)
-    pub clockid: Option<i32>,
+    pub clock: Option<Clock>,
 
     /// This specifies how much data is required to trigger a PERF_RECORD_AUX sample. (since Linux
     /// 4.1)
     pub aux_watermark: Option<u32>,
+
+    /// The flags passed to perf_event_open(2). Defaults to `OpenFlags::FD_CLOEXEC`, so that the
+    /// file descriptors aren't inherited by commands this process runs.
+    pub flags: OpenFlags,
 }
 
 impl ::std::default::Default for EventConfig {
     fn default() -> Self {
         EventConfig {
             aux_watermark: None,
-            clockid: None,
+            flags: OpenFlags::default(),
+            clock: None,
             exclude_guest: true,
             exclude_host: false,
             inherit_stat: false,
@@ -257,9 +444,9 @@ impl EventConfig {
             attr.aux_watermark = mark;
         }
 
-        if let Some(clock) = self.clockid {
+        if let Some(clock) = self.clock {
             attr.set_use_clockid(1);
-            attr.clockid = clock;
+            attr.clockid = clock.raw();
         }
 
         attr.size = size_of::<perf_event_attr>() as u32;
@@ -271,19 +458,15 @@ impl EventConfig {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PidConfig {
     Current,
     Other(pid_t),
-}
-
-impl PidConfig {
-    fn raw(&self) -> pid_t {
-        match *self {
-            PidConfig::Current => 0,
-            PidConfig::Other(p) => p,
-        }
-    }
+    /// Measures every task in a cgroup v2 directory, e.g. /sys/fs/cgroup/system.slice. The
+    /// kernel only measures cgroups per CPU, so counters and `BlockingSampler`s open events with
+    /// `CpuConfig::All` once for each online CPU. The other samplers write to a single ring buffer,
+    /// which needs a `CpuConfig::Specific`. (since Linux 2.6.39)
+    Cgroup(PathBuf),
 }
 
 impl Default for PidConfig {
@@ -292,7 +475,18 @@ impl Default for PidConfig {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+impl Display for PidConfig {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            PidConfig::Current => f.write_str("the calling thread"),
+            PidConfig::Other(-1) => f.write_str("all processes"),
+            PidConfig::Other(pid) => write!(f, "pid {}", pid),
+            PidConfig::Cgroup(ref path) => write!(f, "cgroup {}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum CpuConfig {
     All,
     Specific(i32),
@@ -313,6 +507,15 @@ impl Default for CpuConfig {
     }
 }
 
+impl Display for CpuConfig {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            CpuConfig::All => f.write_str("any CPU"),
+            CpuConfig::Specific(cpu) => write!(f, "CPU {}", cpu),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -356,4 +559,145 @@ mod test {
 
         debug!("events collected: {:#?}", first_events);
     }
+
+    #[test]
+    fn fds_close_on_exec() {
+        let close_on_exec = |flags: OpenFlags| {
+            let config = CountConfig {
+                event: Counted::Software(SwEvent::TaskClock),
+                shared: EventConfig {
+                    flags,
+                    ..EventConfig::default()
+                },
+            };
+            let counter = Counter::new(config).unwrap();
+            let fd_flags = unsafe { libc::fcntl(counter.raw_fds()[0], libc::F_GETFD) };
+            fd_flags & libc::FD_CLOEXEC != 0
+        };
+
+        assert!(close_on_exec(OpenFlags::default()));
+        assert!(!close_on_exec(OpenFlags::empty()));
+    }
+
+    #[test]
+    fn counts_with_fallbacks() {
+        let cycles = Counted::Hardware(HwEvent::CpuCycles);
+        let task_clock = Counted::Software(SwEvent::TaskClock);
+        let report = Perf::new(EventConfig::default())
+            .count_with_fallbacks(cycles, vec![Fallback::Event(task_clock)])
+            .count(task_clock)
+            .create();
+        assert!(report.is_complete());
+
+        // which one is opened depends on whether this machine has a PMU
+        let mut perf = report.into_perf().unwrap();
+        let opened = perf.opened()[0].clone();
+        assert_eq!(opened.requested, cycles);
+        if opened.fell_back() {
+            assert_eq!(opened.event, task_clock);
+            assert_eq!(opened.fallbacks, vec![Fallback::Event(task_clock)]);
+        } else {
+            assert_eq!(opened.event, cycles);
+        }
+
+        // both are reported even if they counted the same event
+        let counts = perf.read();
+        assert!(counts.contains_key(&cycles));
+        assert!(counts.contains_key(&task_clock));
+        let report = perf.report();
+        assert_eq!(
+            report.fell_back.get(&cycles) == Some(&task_clock),
+            opened.fell_back()
+        );
+    }
+
+    #[test]
+    fn failures_group_by_kind() {
+        let cycles = Counted::Hardware(HwEvent::CpuCycles);
+        let instructions = Counted::Hardware(HwEvent::Instructions);
+        let task_clock = Counted::Software(SwEvent::TaskClock);
+
+        let fail = |inner| {
+            Error::open(
+                inner,
+                &perf_event_attr::default(),
+                &PidConfig::Current,
+                CpuConfig::All,
+            )
+        };
+        let mut failures = BTreeMap::new();
+        failures.insert(cycles, fail(fd::OpenError::InvalidEventType));
+        failures.insert(instructions, fail(fd::OpenError::InvalidEventType));
+        failures.insert(task_clock, fail(fd::OpenError::TooManyOpenFiles));
+        let failures = Failures(failures);
+
+        let grouped = failures.by_kind();
+        assert_eq!(
+            grouped[&FailureKind::Unsupported],
+            vec![cycles, instructions]
+        );
+        assert_eq!(grouped[&FailureKind::FdLimit], vec![task_clock]);
+        assert_eq!(
+            failures.to_string(),
+            format!(
+                "unsupported: {}, {}; out of file descriptors: {}",
+                cycles, instructions, task_clock
+            )
+        );
+    }
+
+    /// The cgroup v2 directory this process is in.
+    pub(crate) fn own_cgroup() -> PathBuf {
+        let cgroups = ::std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        let path = cgroups
+            .lines()
+            .find(|line| line.starts_with("0::"))
+            .map(|line| line["0::".len()..].trim_start_matches('/'))
+            .unwrap();
+
+        let mounts = ::std::fs::read_to_string("/proc/self/mounts").unwrap();
+        let root = mounts
+            .lines()
+            .map(|line| line.split(' ').collect::<Vec<_>>())
+            .find(|fields| fields[2] == "cgroup2")
+            .map(|fields| PathBuf::from(fields[1]))
+            .unwrap();
+
+        root.join(path)
+    }
+
+    /// Keeps this thread busy for a few milliseconds, so that clocks and counters measuring it
+    /// have something to count.
+    pub(crate) fn burn_cpu() {
+        let mut sum = 0u64;
+        for n in 0..1_000_000u64 {
+            sum = sum.wrapping_add(n * n);
+        }
+        assert_ne!(sum, 1);
+    }
+
+    #[test]
+    fn cgroup_counts() {
+        let config = EventConfig {
+            pid: PidConfig::Cgroup(own_cgroup()),
+            ..EventConfig::default()
+        };
+        let task_clock = Counted::Software(SwEvent::TaskClock);
+        let mut counts = Perf::new(config).count(task_clock).create().into_perf().unwrap();
+        counts.start().unwrap();
+
+        burn_cpu();
+        assert!(counts.read()[&task_clock] > 0);
+
+        let missing = EventConfig {
+            pid: PidConfig::Cgroup(PathBuf::from("/nonexistent/cgroup")),
+            ..EventConfig::default()
+        };
+        let mut report = Perf::new(missing).count(task_clock).create();
+        assert!(report.perf.is_none());
+        match report.failures.0.remove(&task_clock) {
+            Some(Error::Cgroup { .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::Path;
+
+use error::*;
+use fd::PerfEventAttrThingy;
+use raw::perf_event_attr;
+
+/// Where PMU drivers list their type, event aliases and config formats.
+const SYSFS_PMUS: &str = "/sys/bus/event_source/devices";
+
+/// An event which a PMU driver lists in sysfs, e.g. cpu/topdown-retiring/, encoded from its
+/// terms (e.g. "event=0x00,umask=0x80") with the PMU's format files (e.g. umask is
+/// "config:8-15").
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PmuEvent {
+    pub name: String,
+    /// The PMU's dynamic perf_type_id.
+    pub type_: u32,
+    /// config, config1 and config2.
+    pub config: [u64; 3],
+    /// What to multiply the event's count by, from its .scale file, or 1.
+    pub scale: f64,
+}
+
+impl PmuEvent {
+    /// Looks up `name` in the events which `pmu` lists.
+    pub(crate) fn find(pmu: &str, name: &str) -> Result<Self> {
+        Self::find_in(&Path::new(SYSFS_PMUS).join(pmu), name)
+    }
+
+    /// Whether `pmu` lists an event called `name`.
+    pub(crate) fn exists(pmu: &str, name: &str) -> bool {
+        Path::new(SYSFS_PMUS)
+            .join(pmu)
+            .join("events")
+            .join(name)
+            .exists()
+    }
+
+    fn find_in(dir: &Path, name: &str) -> Result<Self> {
+        let type_ = parse_number(&read(&dir.join("type"))?)
+            .map(|t| t as u32)
+            .ok_or_else(|| malformed(&dir.join("type")))?;
+
+        let events = dir.join("events");
+        let terms = read(&events.join(name))?;
+        let mut config = [0; 3];
+        for term in terms.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            // a term without a value, e.g. "edge", sets its bits to 1
+            let mut parts = term.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = match parts.next() {
+                Some(value) => parse_number(value).ok_or_else(|| malformed(&events.join(name)))?,
+                None => 1,
+            };
+
+            let format = dir.join("format").join(key);
+            let (field, bits) = parse_format(&read(&format)?).ok_or_else(|| malformed(&format))?;
+            config[field] |= deposit(value, &bits);
+        }
+
+        let scale = match fs::read_to_string(events.join(format!("{}.scale", name))) {
+            Ok(scale) => scale
+                .trim()
+                .parse()
+                .map_err(|_| malformed(&events.join(format!("{}.scale", name))))?,
+            Err(_) => 1.0,
+        };
+
+        Ok(PmuEvent {
+            name: name.to_owned(),
+            type_,
+            config,
+            scale,
+        })
+    }
+}
+
+impl PerfEventAttrThingy for PmuEvent {
+    fn apply(&self, attr: &mut perf_event_attr) {
+        attr.type_ = self.type_;
+        attr.config = self.config[0];
+        attr.__bindgen_anon_3.config1 = self.config[1];
+        attr.__bindgen_anon_4.config2 = self.config[2];
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|inner| Error::Proc {
+        path: path.to_owned(),
+        inner,
+    })
+}
+
+fn malformed(path: &Path) -> Error {
+    Error::Misc {
+        inner: format!("unexpected contents in {}", path.display()),
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parses a format like "config:0-7,32-35" or "config1:9" into which of config, config1 and
+/// config2 it's in, and its bit ranges from least to most significant.
+fn parse_format(format: &str) -> Option<(usize, Vec<(u32, u32)>)> {
+    let mut parts = format.trim().splitn(2, ':');
+    let field = match parts.next()? {
+        "config" => 0,
+        "config1" => 1,
+        "config2" => 2,
+        _ => return None,
+    };
+
+    let mut bits = Vec::new();
+    for range in parts.next()?.split(',') {
+        let mut ends = range.splitn(2, '-');
+        let low = ends.next()?.parse().ok()?;
+        let high = match ends.next() {
+            Some(high) => high.parse().ok()?,
+            None => low,
+        };
+        if high < low || high > 63 {
+            return None;
+        }
+        bits.push((low, high));
+    }
+    Some((field, bits))
+}
+
+/// Spreads `value`'s bits across the ranges, lowest first.
+fn deposit(mut value: u64, bits: &[(u32, u32)]) -> u64 {
+    let mut config = 0;
+    for &(low, high) in bits {
+        let width = high - low + 1;
+        let mask = if width == 64 { !0 } else { (1 << width) - 1 };
+        config |= (value & mask) << low;
+        value = value.checked_shr(width).unwrap_or(0);
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::process;
+
+    #[test]
+    fn encodes_sysfs_events() {
+        let dir = temp_dir().join(format!("perf_events-pmu-{}", process::id()));
+        fs::create_dir_all(dir.join("events")).unwrap();
+        fs::create_dir_all(dir.join("format")).unwrap();
+        let write = |path: &str, contents: &str| fs::write(dir.join(path), contents).unwrap();
+        write("type", "4\n");
+        write("format/event", "config:0-7\n");
+        write("format/umask", "config:8-15\n");
+        write("format/any", "config:21\n");
+        write("format/cmask", "config:24-31\n");
+        write("format/split", "config1:0-3,8-11\n");
+        write("events/topdown-retiring", "event=0x00,umask=0x80\n");
+        write("events/topdown-total-slots", "event=0x3c,umask=0x0,any=1\n");
+        write("events/topdown-total-slots.scale", "4\n");
+        write("events/odd", "event=0x1,split=0xab,cmask=3\n");
+
+        let retiring = PmuEvent::find_in(&dir, "topdown-retiring").unwrap();
+        assert_eq!(retiring.type_, 4);
+        assert_eq!(retiring.config, [0x8000, 0, 0]);
+        assert_eq!(retiring.scale, 1.0);
+
+        let slots = PmuEvent::find_in(&dir, "topdown-total-slots").unwrap();
+        assert_eq!(slots.config, [0x20003c, 0, 0]);
+        assert_eq!(slots.scale, 4.0);
+
+        let odd = PmuEvent::find_in(&dir, "odd").unwrap();
+        assert_eq!(odd.config, [0x0300_0001, 0xa0b, 0]);
+
+        assert!(PmuEvent::find_in(&dir, "missing").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
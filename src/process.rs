@@ -0,0 +1,499 @@
+use std::path::{Path, PathBuf};
+use std::{collections::BTreeMap, fs};
+
+use libc::{self, pid_t};
+
+use count::{CountConfig, Counted, Counter};
+use error::*;
+use fd::OpenError;
+use sample::{
+    blocking::BlockingSampler,
+    config::{SamplingConfig, SamplingRate},
+    record::RecordContents,
+};
+use {CpuConfig, EventConfig, PidConfig};
+
+/// Counters attached to every thread of an already-running process, like `perf stat -p`.
+///
+/// `PidConfig::Other` only measures the single thread it names, and `inherit` only applies to
+/// threads created after an event is opened. Attaching opens a counter on each thread listed in
+/// /proc/\<pid\>/task, with `inherit` set so that threads they start later are counted too.
+/// Those later threads are added to the count of the thread which created them.
+pub struct ProcessCounter {
+    pid: pid_t,
+    /// Each attached thread's counters, one per event.
+    threads: BTreeMap<pid_t, Vec<Counter>>,
+}
+
+impl ProcessCounter {
+    /// Opens (disabled) counters for `events` on each of `pid`'s threads. `shared.pid` is
+    /// ignored, and `shared.inherit` is always set.
+    ///
+    /// Threads can start while the counters are being opened, so the thread list is read until
+    /// it stops changing. Threads which exit before they can be attached are skipped.
+    pub fn attach(pid: pid_t, shared: EventConfig, events: &[Counted]) -> Result<Self> {
+        let mut threads = BTreeMap::new();
+
+        loop {
+            let mut attached = 0;
+            for tid in process_threads(pid)? {
+                if threads.contains_key(&tid) {
+                    continue;
+                }
+
+                let shared = EventConfig {
+                    pid: PidConfig::Other(tid),
+                    inherit: true,
+                    ..shared
+                };
+
+                let counters = events
+                    .iter()
+                    .map(|&event| {
+                        Counter::new(CountConfig {
+                            event,
+                            shared: shared.clone(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>();
+
+                match counters {
+                    Ok(counters) => {
+                        threads.insert(tid, counters);
+                        attached += 1;
+                    }
+                    Err(Error::FdOpen {
+                        inner: OpenError::ProcessDoesNotExist,
+                        ..
+                    }) => debug!("thread {} of {} exited before it was attached", tid, pid),
+                    Err(why) => return Err(why),
+                }
+            }
+
+            if attached == 0 {
+                break;
+            }
+        }
+
+        if threads.is_empty() {
+            return Err(Error::NoSuchProcess { pid });
+        }
+
+        debug!("attached to {} threads of {}", threads.len(), pid);
+        Ok(Self { pid, threads })
+    }
+
+    /// The process's ID.
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    /// The thread IDs which were attached to, including any which have since exited.
+    pub fn threads(&self) -> Vec<pid_t> {
+        self.threads.keys().cloned().collect()
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        for counter in self.threads.values().flat_map(|c| c.iter()) {
+            counter.enable()?;
+        }
+        Ok(())
+    }
+
+    /// Reads each event's count, summed across all of the process's threads.
+    pub fn read(&mut self) -> Result<BTreeMap<Counted, u64>> {
+        let mut totals = BTreeMap::new();
+        for counts in self.read_per_thread()?.values() {
+            for (&event, &count) in counts {
+                *totals.entry(event).or_insert(0) += count;
+            }
+        }
+        Ok(totals)
+    }
+
+    /// Reads each event's count for each attached thread. A thread's counts include those of
+    /// the threads it started after it was attached.
+    pub fn read_per_thread(&mut self) -> Result<BTreeMap<pid_t, BTreeMap<Counted, u64>>> {
+        let mut per_thread = BTreeMap::new();
+        for (&tid, counters) in &mut self.threads {
+            let counts = counters
+                .iter_mut()
+                .map(Counter::read)
+                .collect::<Result<BTreeMap<_, _>>>()?;
+            per_thread.insert(tid, counts);
+        }
+        Ok(per_thread)
+    }
+}
+
+/// Lists the IDs of a process's current threads from /proc/\<pid\>/task, e.g. to sample each of
+/// them with `PidConfig::Other`.
+pub fn process_threads(pid: pid_t) -> Result<Vec<pid_t>> {
+    let path = PathBuf::from(format!("/proc/{}/task", pid));
+    let entries = fs::read_dir(&path).map_err(|inner| {
+        if inner.kind() == ::std::io::ErrorKind::NotFound {
+            Error::NoSuchProcess { pid }
+        } else {
+            Error::Proc {
+                path: path.clone(),
+                inner,
+            }
+        }
+    })?;
+
+    let mut threads = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|inner| Error::Proc {
+            path: path.clone(),
+            inner,
+        })?;
+        if let Some(tid) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            threads.push(tid);
+        }
+    }
+    threads.sort();
+    Ok(threads)
+}
+
+/// Counts an event in a task and in every thread and process it starts afterwards, broken down by
+/// thread, like `perf stat --per-thread`.
+///
+/// perf_event_open(2) describes `inherit_stat` as writing a PERF_RECORD_READ with each inherited
+/// child's final count when it exits, but current kernels tear down the child's events before
+/// writing it, so it can't be relied on. Instead, inherited events on each online CPU (the kernel
+/// only allows ring buffers on inherited events which are opened for a specific CPU) write
+/// PERF_RECORD_FORK, PERF_RECORD_COMM and PERF_RECORD_EXIT records, which are used to open a
+/// counter on each new thread, name it, and read its final count when it exits.
+///
+/// A new thread isn't counted until its PERF_RECORD_FORK has been read by `poll`, so it should be
+/// called often. Anything a thread does before then, or all of it if it exits first, is still
+/// included in `total`.
+pub struct ThreadCounter {
+    event: Counted,
+    shared: EventConfig,
+    enabled: bool,
+    sampler: BlockingSampler,
+    running: BTreeMap<u32, (ThreadCount, Counter)>,
+    exited: BTreeMap<u32, ThreadCount>,
+}
+
+/// The count of an event for a single thread.
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct ThreadCount {
+    pub pid: u32,
+    pub tid: u32,
+    /// The thread's most recent name, or empty if it wasn't known.
+    pub comm: String,
+    pub count: u64,
+    pub exited: bool,
+}
+
+impl ThreadCounter {
+    /// Opens (disabled) counters for `event` in `shared.pid`, which can't be a cgroup.
+    /// `shared.cpu` is ignored, and `shared.inherit` is always set.
+    pub fn new(event: Counted, shared: EventConfig) -> Result<Self> {
+        let tid = match shared.pid {
+            // NOTE(unsafe) gettid can't fail
+            PidConfig::Current => unsafe { libc::syscall(libc::SYS_gettid) as pid_t },
+            PidConfig::Other(tid) => tid,
+            PidConfig::Cgroup(_) => {
+                return Err(Error::Misc {
+                    inner: "threads can only be followed from a task".into(),
+                })
+            }
+        };
+
+        let buffers = online_cpus()?
+            .into_iter()
+            .map(|cpu| {
+                vec![SamplingConfig {
+                    shared: EventConfig {
+                        cpu: CpuConfig::Specific(cpu),
+                        inherit: true,
+                        ..shared.clone()
+                    },
+                    event,
+                    // a sample_period of zero counts without sampling
+                    rate: SamplingRate::Period(0),
+                    requests: Vec::new(),
+                    sample_id_all: false,
+                    mmap: false,
+                    mmap2: false,
+                    comm: true,
+                    comm_exec: false,
+                    task: true,
+                    enable_on_exec: false,
+                    precise_ip: 0,
+                    ..SamplingConfig::default()
+                }]
+            })
+            .collect();
+
+        let mut counter = Self {
+            event,
+            shared,
+            enabled: false,
+            sampler: BlockingSampler::open(buffers)?,
+            running: BTreeMap::new(),
+            exited: BTreeMap::new(),
+        };
+
+        let comm = fs::read_to_string(format!("/proc/{}/comm", tid))
+            .map(|comm| comm.trim_end().to_owned())
+            .unwrap_or_default();
+        let pid = fs::read_to_string(format!("/proc/{}/status", tid))
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find(|line| line.starts_with("Tgid:"))
+                    .and_then(|line| line["Tgid:".len()..].trim().parse().ok())
+            })
+            .unwrap_or(tid as u32);
+        counter.attach(pid, tid as u32, comm)?;
+
+        Ok(counter)
+    }
+
+    pub fn enable(&mut self) -> Result<()> {
+        self.sampler.enable()?;
+        for (_, counter) in self.running.values() {
+            counter.enable()?;
+        }
+        self.enabled = true;
+        Ok(())
+    }
+
+    /// Reads the records the kernel has written so far, attaching to threads which have started,
+    /// renaming threads which have changed their names, and reading the final counts of threads
+    /// which have exited.
+    pub fn poll(&mut self) -> Result<()> {
+        let mut records = Vec::new();
+        self.sampler.drain(|record| records.push(record.contents))?;
+
+        // the buffers are drained one after another, so a thread's rename or exit can be read
+        // before its start if they happened on different CPUs
+        for contents in &records {
+            if let RecordContents::Fork(fork) = *contents {
+                let comm = self
+                    .running
+                    .get(&fork.ptid)
+                    .map(|(parent, _)| parent.comm.clone())
+                    .unwrap_or_default();
+                self.attach(fork.pid, fork.tid, comm)?;
+            }
+        }
+
+        let mut exits = Vec::new();
+        for contents in records {
+            match contents {
+                RecordContents::Comm { tid, comm, .. } => {
+                    if let Some((thread, _)) = self.running.get_mut(&tid) {
+                        thread.comm = comm;
+                    }
+                }
+                RecordContents::Exit(exit) => exits.push(exit.tid),
+                _ => (),
+            }
+        }
+
+        for tid in exits {
+            if let Some((mut thread, mut counter)) = self.running.remove(&tid) {
+                thread.count = counter.read()?.1;
+                thread.exited = true;
+                self.exited.insert(tid, thread);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Polls for new records, then reads the count of every thread which has been attached to,
+    /// keyed by thread ID. Threads which have exited keep their final count.
+    pub fn read_per_thread(&mut self) -> Result<BTreeMap<u32, ThreadCount>> {
+        self.poll()?;
+
+        let mut counts = self.exited.clone();
+        for (&tid, &mut (ref thread, ref mut counter)) in &mut self.running {
+            let count = counter.read()?.1;
+            counts.insert(
+                tid,
+                ThreadCount {
+                    count,
+                    ..thread.clone()
+                },
+            );
+        }
+        Ok(counts)
+    }
+
+    /// Reads the count for the task and all of its descendants, including those still running.
+    pub fn total(&self) -> Result<u64> {
+        Ok(self.sampler.read_counts()?.into_iter().sum())
+    }
+
+    fn attach(&mut self, pid: u32, tid: u32, comm: String) -> Result<()> {
+        let shared = EventConfig {
+            pid: PidConfig::Other(tid as pid_t),
+            cpu: CpuConfig::All,
+            inherit: false,
+            inherit_stat: false,
+            ..self.shared.clone()
+        };
+
+        let counter = match Counter::new(CountConfig {
+            event: self.event,
+            shared,
+        }) {
+            Ok(counter) => counter,
+            Err(Error::FdOpen {
+                inner: OpenError::ProcessDoesNotExist,
+                ..
+            }) => {
+                debug!("thread {} exited before it was attached", tid);
+                return Ok(());
+            }
+            Err(why) => return Err(why),
+        };
+        if self.enabled {
+            counter.enable()?;
+        }
+
+        let thread = ThreadCount {
+            pid,
+            tid,
+            comm,
+            count: 0,
+            exited: false,
+        };
+        self.running.insert(tid, (thread, counter));
+        Ok(())
+    }
+}
+
+/// Lists the CPUs which are currently online, from /sys/devices/system/cpu/online.
+pub fn online_cpus() -> Result<Vec<i32>> {
+    let path = Path::new("/sys/devices/system/cpu/online");
+    let online = fs::read_to_string(path).map_err(|inner| Error::Proc {
+        path: path.to_owned(),
+        inner,
+    })?;
+    parse_cpu_list(&online)
+}
+
+/// Parses a kernel CPU list like "0-3,8,10-11".
+fn parse_cpu_list(list: &str) -> Result<Vec<i32>> {
+    let invalid = || Error::Misc {
+        inner: format!("invalid CPU list: {:?}", list),
+    };
+
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut ends = range.splitn(2, '-').map(|n| n.parse::<i32>());
+        let start = ends.next().unwrap().map_err(|_| invalid())?;
+        let end = match ends.next() {
+            Some(end) => end.map_err(|_| invalid())?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel;
+    use std::thread;
+    use SwEvent;
+
+    #[test]
+    fn attaches_to_existing_threads() {
+        let (tid_tx, tid_rx) = channel::unbounded();
+        let (stop_tx, stop_rx) = channel::unbounded::<()>();
+
+        let worker = thread::spawn(move || {
+            tid_tx.send(unsafe { ::libc::syscall(::libc::SYS_gettid) } as pid_t);
+            while stop_rx.try_recv().is_none() {
+                ::test::burn_cpu();
+            }
+        });
+        let worker_tid = tid_rx.recv().unwrap();
+
+        let pid = unsafe { ::libc::getpid() };
+        assert!(process_threads(pid).unwrap().contains(&worker_tid));
+
+        let task_clock = Counted::Software(SwEvent::TaskClock);
+        let mut counter =
+            ProcessCounter::attach(pid, EventConfig::default(), &[task_clock]).unwrap();
+        assert!(counter.threads().contains(&worker_tid));
+        counter.enable().unwrap();
+
+        thread::sleep(::std::time::Duration::from_millis(50));
+        stop_tx.send(());
+        worker.join().unwrap();
+
+        let per_thread = counter.read_per_thread().unwrap();
+        let worker_count = per_thread[&worker_tid][&task_clock];
+        assert!(worker_count > 0);
+        assert!(counter.read().unwrap()[&task_clock] >= worker_count);
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0\n").unwrap(), vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert!(parse_cpu_list("0-a").is_err());
+        assert!(!online_cpus().unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_per_thread() {
+        let task_clock = Counted::Software(SwEvent::TaskClock);
+        let mut counter = ThreadCounter::new(task_clock, EventConfig::default()).unwrap();
+        counter.enable().unwrap();
+
+        let (started_tx, started_rx) = channel::unbounded();
+        let (stop_tx, stop_rx) = channel::unbounded::<()>();
+        let worker = thread::Builder::new()
+            .name("burner".into())
+            .spawn(move || {
+                started_rx.recv().unwrap();
+                for _ in 0..10 {
+                    ::test::burn_cpu();
+                }
+                stop_rx.recv().unwrap();
+                (unsafe { ::libc::syscall(::libc::SYS_gettid) } as u32)
+            })
+            .unwrap();
+
+        // let the worker start burning once it's been attached to
+        counter.poll().unwrap();
+        started_tx.send(());
+        thread::sleep(::std::time::Duration::from_millis(50));
+        let running = counter.read_per_thread().unwrap();
+        assert!(running.values().any(|t| t.comm == "burner" && !t.exited));
+
+        stop_tx.send(());
+        let worker_tid = worker.join().unwrap();
+
+        // the thread can be joined before the kernel has written its exit
+        let mut worker = None;
+        for _ in 0..100 {
+            let counts = counter.read_per_thread().unwrap();
+            worker = counts.get(&worker_tid).cloned().filter(|t| t.exited);
+            if worker.is_some() {
+                break;
+            }
+            thread::sleep(::std::time::Duration::from_millis(10));
+        }
+
+        let worker = worker.unwrap();
+        assert_eq!(worker.comm, "burner");
+        assert!(worker.count > 0);
+        assert!(counter.total().unwrap() >= worker.count);
+    }
+}
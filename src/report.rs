@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+use std::time::Duration;
+
+use serde_json;
+
+use count::{Counted, HwEvent, SwEvent};
+use fallback::Opened;
+
+/// A snapshot of counts, with the metrics which can be derived from whichever events were
+/// counted, e.g. from `Perf::report`.
+///
+/// Serializes with each event keyed by its name, e.g. "cpu-cycles". `to_csv` and the `Display`
+/// impl list one event per row, each annotated with the metric it's the numerator of, like
+/// perf-stat(1).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CountReport {
+    /// Keyed by the requested event, even if another was counted in its place.
+    pub counts: BTreeMap<Counted, u64>,
+    /// The event which was counted in place of each requested event that fell back to another.
+    /// Metrics aren't derived from these events' counts.
+    pub fell_back: BTreeMap<Counted, Counted>,
+    /// The percentage of the time each multiplexed event was enabled that it was actually
+    /// counting, for the events whose counts were scaled up to estimate the whole time.
+    pub running_percent: BTreeMap<Counted, f64>,
+    /// The wall-clock time the counters were enabled for, in seconds.
+    pub elapsed_secs: f64,
+    pub metrics: Metrics,
+}
+
+/// Ratios between common events. Each is `None` unless both of the events it needs were counted
+/// and its denominator isn't zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Metrics {
+    /// Instructions retired per CPU cycle.
+    pub instructions_per_cycle: Option<f64>,
+    /// Cache misses as a percentage of cache references.
+    pub cache_miss_percent: Option<f64>,
+    /// Branch misses as a percentage of branch instructions.
+    pub branch_miss_percent: Option<f64>,
+    /// Cycles stalled during issue, as a percentage of CPU cycles.
+    pub frontend_stall_percent: Option<f64>,
+    /// Cycles stalled during retirement, as a percentage of CPU cycles.
+    pub backend_stall_percent: Option<f64>,
+    /// Page faults per second of wall-clock time.
+    pub faults_per_second: Option<f64>,
+}
+
+impl CountReport {
+    /// Derives the metrics from `counts`, keyed by requested event, where `opened` says which
+    /// events fell back to others, e.g. from `Perf::opened`.
+    pub fn new(counts: BTreeMap<Counted, u64>, opened: &[Opened], elapsed: Duration) -> Self {
+        let elapsed_secs = elapsed.as_secs_f64();
+        let fell_back = opened
+            .iter()
+            .filter(|o| o.event != o.requested)
+            .map(|o| (o.requested, o.event))
+            .collect::<BTreeMap<_, _>>();
+        let count = |event| {
+            if fell_back.contains_key(&event) {
+                None
+            } else {
+                counts.get(&event).map(|&c| c as f64)
+            }
+        };
+        let ratio = |numerator, denominator| match (count(numerator), count(denominator)) {
+            (Some(n), Some(d)) if d > 0.0 => Some(n / d),
+            _ => None,
+        };
+        let percent = |numerator, denominator| ratio(numerator, denominator).map(|r| r * 100.0);
+
+        let cycles = Counted::Hardware(HwEvent::CpuCycles);
+        let metrics = Metrics {
+            instructions_per_cycle: ratio(Counted::Hardware(HwEvent::Instructions), cycles),
+            cache_miss_percent: percent(
+                Counted::Hardware(HwEvent::CacheMisses),
+                Counted::Hardware(HwEvent::CacheReferences),
+            ),
+            branch_miss_percent: percent(
+                Counted::Hardware(HwEvent::BranchMisses),
+                Counted::Hardware(HwEvent::BranchInstructions),
+            ),
+            frontend_stall_percent: percent(
+                Counted::Hardware(HwEvent::StalledCyclesFrontend),
+                cycles,
+            ),
+            backend_stall_percent: percent(
+                Counted::Hardware(HwEvent::StalledCyclesBackend),
+                cycles,
+            ),
+            faults_per_second: match count(Counted::Software(SwEvent::PageFaults)) {
+                Some(faults) if elapsed_secs > 0.0 => Some(faults / elapsed_secs),
+                _ => None,
+            },
+        };
+
+        CountReport {
+            counts,
+            fell_back,
+            running_percent: BTreeMap::new(),
+            elapsed_secs,
+            metrics,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        // every key is a string and every value a number, so this can't fail
+        serde_json::to_string(self).expect("count reports always serialize")
+    }
+
+    /// One row per event with the columns
+    /// "event,counted_as,count,metric,metric_value,running_percent", where counted_as is empty
+    /// unless the event fell back to another, the metric columns are empty for events which
+    /// aren't a metric's numerator, and running_percent is empty unless the count was scaled.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("event,counted_as,count,metric,metric_value,running_percent\n");
+        for (event, count) in &self.counts {
+            let counted_as = self
+                .fell_back
+                .get(event)
+                .map_or(String::new(), Counted::name);
+            let (metric, value) = match self.annotation(*event) {
+                Some((value, unit)) => (unit, value.to_string()),
+                None => ("", String::new()),
+            };
+            let running = self
+                .running_percent
+                .get(event)
+                .map_or(String::new(), f64::to_string);
+            // writing to a String can't fail
+            writeln!(
+                csv,
+                "{},{},{},{},{},{}",
+                event.name(),
+                counted_as,
+                count,
+                metric,
+                value,
+                running
+            )
+            .unwrap();
+        }
+        csv
+    }
+
+    /// The metric which `event` is the numerator of, if it was derived, and its unit.
+    fn annotation(&self, event: Counted) -> Option<(f64, &'static str)> {
+        let m = &self.metrics;
+        let (value, unit) = match event {
+            Counted::Hardware(HwEvent::Instructions) => {
+                (m.instructions_per_cycle, "insn per cycle")
+            }
+            Counted::Hardware(HwEvent::CacheMisses) => (m.cache_miss_percent, "% of cache refs"),
+            Counted::Hardware(HwEvent::BranchMisses) => {
+                (m.branch_miss_percent, "% of all branches")
+            }
+            Counted::Hardware(HwEvent::StalledCyclesFrontend) => {
+                (m.frontend_stall_percent, "% frontend cycles idle")
+            }
+            Counted::Hardware(HwEvent::StalledCyclesBackend) => {
+                (m.backend_stall_percent, "% backend cycles idle")
+            }
+            Counted::Software(SwEvent::PageFaults) => (m.faults_per_second, "/sec"),
+            _ => return None,
+        };
+        value.map(|value| (value, unit))
+    }
+}
+
+/// Formats like `perf stat`, with the percentage of the time each scaled count was running, e.g.
+///
+/// ```text
+///      1,234,567,890      cpu-cycles                (50.00%)
+///      2,345,678,901      instructions             #     1.90 insn per cycle
+///
+///        0.502113184 seconds time elapsed
+/// ```
+impl Display for CountReport {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        for (event, count) in &self.counts {
+            write!(f, "{:>18}      {:<24}", thousands(*count), event.name())?;
+            if let Some((value, unit)) = self.annotation(*event) {
+                let space = if unit.starts_with('%') { "" } else { " " };
+                write!(f, " # {:>8.2}{}{}", value, space, unit)?;
+            } else if let Some(counted_as) = self.fell_back.get(event) {
+                write!(f, " # counted as {}", counted_as.name())?;
+            }
+            if let Some(percent) = self.running_percent.get(event) {
+                write!(f, "  ({:.2}%)", percent)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:>18.9} seconds time elapsed", self.elapsed_secs)
+    }
+}
+
+/// Groups a count's digits in threes, e.g. "1,234,567".
+fn thousands(count: u64) -> String {
+    let digits = count.to_string();
+    let groups = digits
+        .as_bytes()
+        .rchunks(3)
+        .rev()
+        .map(|group| String::from_utf8_lossy(group))
+        .collect::<Vec<_>>();
+    groups.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use count::scale_readings;
+    use fallback::Fallback;
+
+    #[test]
+    fn derives_metrics() {
+        let mut counts = BTreeMap::new();
+        counts.insert(Counted::Hardware(HwEvent::CpuCycles), 2_000_000);
+        counts.insert(Counted::Hardware(HwEvent::Instructions), 3_000_000);
+        counts.insert(Counted::Hardware(HwEvent::CacheReferences), 0);
+        counts.insert(Counted::Hardware(HwEvent::CacheMisses), 10);
+        counts.insert(Counted::Hardware(HwEvent::BranchInstructions), 400);
+        counts.insert(Counted::Hardware(HwEvent::BranchMisses), 10);
+        counts.insert(Counted::Software(SwEvent::PageFaults), 50);
+
+        let report = CountReport::new(counts, &[], Duration::from_millis(500));
+        assert_eq!(
+            report.metrics,
+            Metrics {
+                instructions_per_cycle: Some(1.5),
+                cache_miss_percent: None,
+                branch_miss_percent: Some(2.5),
+                frontend_stall_percent: None,
+                backend_stall_percent: None,
+                faults_per_second: Some(100.0),
+            }
+        );
+
+        let table = report.to_string();
+        assert!(table.contains("         2,000,000      cpu-cycles"));
+        assert!(table.contains("instructions             #     1.50 insn per cycle"));
+        assert!(table.contains("#     2.50% of all branches"));
+        assert!(table.contains("0.500000000 seconds time elapsed"));
+
+        let csv = report.to_csv();
+        assert!(csv.starts_with("event,counted_as,count,metric,metric_value,running_percent\n"));
+        assert!(csv.contains("\ninstructions,,3000000,insn per cycle,1.5,\n"));
+        assert!(csv.contains("\ncache-misses,,10,,,\n"));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["counts"]["page-fault"], 50);
+        assert_eq!(json["metrics"]["faults_per_second"], 100.0);
+        assert!(json["metrics"]["cache_miss_percent"].is_null());
+    }
+
+    #[test]
+    fn ignores_events_which_fell_back() {
+        let cycles = Counted::Hardware(HwEvent::CpuCycles);
+        let cpu_clock = Counted::Software(SwEvent::CpuClock);
+        let mut counts = BTreeMap::new();
+        counts.insert(cycles, 1_000);
+        counts.insert(Counted::Hardware(HwEvent::Instructions), 2_000);
+        let opened = Opened {
+            event: cpu_clock,
+            fallbacks: vec![Fallback::Event(cpu_clock)],
+            ..Opened::new(cycles, 0, false, false)
+        };
+
+        let report = CountReport::new(counts, &[opened], Duration::from_millis(500));
+        assert_eq!(report.metrics.instructions_per_cycle, None);
+        assert_eq!(report.fell_back[&cycles], cpu_clock);
+        assert!(report
+            .to_string()
+            .contains("cpu-cycles               # counted as cpu-clock"));
+        assert!(report.to_csv().contains("\ncpu-cycles,cpu-clock,1000,,,\n"));
+    }
+
+    #[test]
+    fn reports_scaled_counts() {
+        let cycles = Counted::Hardware(HwEvent::CpuCycles);
+        let instructions = Counted::Hardware(HwEvent::Instructions);
+        // cycles were multiplexed off the PMU for half of the time they were enabled
+        let readings = [
+            (cycles, scale_readings(&[(1_000, 2_000, 1_000)])),
+            (instructions, scale_readings(&[(3_000, 2_000, 2_000)])),
+        ];
+        let counts = readings.iter().map(|&(e, (count, _))| (e, count)).collect();
+        let running_percent = readings
+            .iter()
+            .filter(|&&(_, (_, percent))| percent < 100.0)
+            .map(|&(e, (_, percent))| (e, percent))
+            .collect();
+
+        let report = CountReport {
+            running_percent,
+            ..CountReport::new(counts, &[], Duration::from_millis(500))
+        };
+        assert_eq!(report.counts[&cycles], 2_000);
+        assert_eq!(report.metrics.instructions_per_cycle, Some(1.5));
+        assert_eq!(report.running_percent[&cycles], 50.0);
+        assert!(!report.running_percent.contains_key(&instructions));
+
+        let table = report.to_string();
+        assert!(table.contains("             2,000      cpu-cycles                (50.00%)\n"));
+        assert!(table.contains("insn per cycle\n"));
+        let csv = report.to_csv();
+        assert!(csv.contains("\ncpu-cycles,,2000,,,50\n"));
+        assert!(csv.contains("\ninstructions,,3000,insn per cycle,1.5,\n"));
+    }
+
+    #[test]
+    fn names_match_serialized_events() {
+        for event in Counted::all() {
+            assert_eq!(serde_json::to_value(event).unwrap(), event.name());
+        }
+    }
+
+    #[test]
+    fn groups_thousands() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(thousands(12_345_678), "12,345,678");
+    }
+}
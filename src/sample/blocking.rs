@@ -0,0 +1,346 @@
+use std::{os::unix::io::AsRawFd, time::Duration};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, EventFlags, PollFd},
+};
+
+use super::{config::SamplingConfig, record::Record, ring_buffer::RingBuffer, SessionStats};
+use error::*;
+use fallback::Opened;
+use fd::AttrExtension;
+use process::online_cpus;
+use self_monitor::PerfClock;
+use {CpuConfig, PidConfig};
+
+/// Reads records from one or more ring buffers on the calling thread, waiting for them with
+/// poll(2) instead of an async runtime. A buffer's file descriptor polls readable once the kernel
+/// has written as many samples or bytes as its events' `WakeupConfig` asks for.
+///
+/// Records are decoded by the same iterator which serves the other samplers. Each `Record`'s
+/// `source` is the index of the config which produced it, counting across all of the buffers in
+/// the order they were passed.
+pub struct BlockingSampler {
+    buffers: Vec<RingBuffer>,
+    /// The index of each buffer's first config in the flattened list of configs.
+    offsets: Vec<usize>,
+    /// Buffers whose events have hung up, e.g. because the task they followed exited.
+    hung_up: Vec<bool>,
+    /// The buffer to start reading from next, so that a busy buffer can't starve the others.
+    next_buffer: usize,
+    stats: SessionStats,
+}
+
+impl BlockingSampler {
+    /// Opens and enables the events, which all write to one ring buffer.
+    pub fn new(sample_configs: Vec<SamplingConfig>) -> Result<Self> {
+        Self::with_buffers(vec![sample_configs])
+    }
+
+    /// Opens and enables a ring buffer for each group of events, e.g. one per CPU.
+    pub fn with_buffers(buffer_configs: Vec<Vec<SamplingConfig>>) -> Result<Self> {
+        let sampler = Self::open(buffer_configs)?;
+        sampler.enable()?;
+        Ok(sampler)
+    }
+
+    /// Opens the ring buffers without enabling their events, for events which are enabled by
+    /// the kernel, e.g. with `enable_on_exec`.
+    ///
+    /// Cgroup events can only be opened for one CPU at a time, so a group of events with a
+    /// `PidConfig::Cgroup` and `CpuConfig::All` is opened with a ring buffer for each online CPU.
+    pub(crate) fn open(buffer_configs: Vec<Vec<SamplingConfig>>) -> Result<Self> {
+        let mut buffers = Vec::new();
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        for sample_configs in buffer_configs {
+            let len = sample_configs.len();
+            let per_cpu = sample_configs.iter().any(|config| {
+                config.shared.cpu == CpuConfig::All
+                    && matches!(config.shared.pid, PidConfig::Cgroup(_))
+            });
+
+            if per_cpu {
+                for cpu in online_cpus()? {
+                    let configs = sample_configs
+                        .iter()
+                        .cloned()
+                        .map(|mut config| {
+                            config.shared.cpu = CpuConfig::Specific(cpu);
+                            config
+                        })
+                        .collect();
+                    offsets.push(offset);
+                    buffers.push(RingBuffer::new(configs)?);
+                }
+            } else {
+                offsets.push(offset);
+                buffers.push(RingBuffer::new(sample_configs)?);
+            }
+            offset += len;
+        }
+
+        Ok(Self {
+            hung_up: vec![false; buffers.len()],
+            buffers,
+            offsets,
+            next_buffer: 0,
+            stats: SessionStats::default(),
+        })
+    }
+
+    pub(crate) fn enable(&self) -> Result<()> {
+        debug!(
+            "enabling {} ring buffers' file descriptors",
+            self.buffers.len()
+        );
+        for buffer in &self.buffers {
+            buffer.enable_fd()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the count of each buffer's first event.
+    pub(crate) fn read_counts(&self) -> Result<Vec<u64>> {
+        self.buffers.iter().map(RingBuffer::read_count).collect()
+    }
+
+    /// Returns the fields of the configs which this kernel doesn't support, and which were
+    /// dropped to open the events.
+    pub fn dropped_fields(&self) -> Vec<AttrExtension> {
+        let mut dropped = self
+            .buffers
+            .iter()
+            .flat_map(RingBuffer::dropped)
+            .collect::<Vec<_>>();
+        dropped.sort();
+        dropped.dedup();
+        dropped
+    }
+
+    /// Returns what was opened for each config of each buffer, after any of their fallbacks. A
+    /// cgroup group opened on every CPU appears once per CPU.
+    pub fn opened(&self) -> Vec<Opened> {
+        self.buffers
+            .iter()
+            .flat_map(|buffer| buffer.opened().iter().cloned())
+            .collect()
+    }
+
+    /// Returns the statistics for the records which have been read so far.
+    pub fn stats(&self) -> SessionStats {
+        self.stats
+    }
+
+    /// Returns the parameters for converting between the hardware clock and the timestamps in
+    /// samples, if the hardware has a constant and nonstop clock.
+    pub fn clock(&self) -> Option<PerfClock> {
+        self.buffers.first().and_then(RingBuffer::clock)
+    }
+
+    /// Blocks until at least one of the buffers has records to read or its events have hung up,
+    /// or until `timeout` has passed, which is rounded up to a whole millisecond. Returns false if
+    /// the timeout passed first, or straight away if every buffer has already hung up.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<bool> {
+        if self.hung_up.iter().all(|&h| h) {
+            return Ok(false);
+        }
+        let timeout = poll_timeout(timeout);
+
+        let mut fds = self
+            .buffers
+            .iter()
+            .zip(&self.hung_up)
+            // poll(2) ignores negative fds, and a hung up fd would never stop being ready
+            .map(|(buffer, &hung_up)| {
+                if hung_up {
+                    PollFd::new(-1, EventFlags::empty())
+                } else {
+                    PollFd::new(buffer.as_raw_fd(), EventFlags::POLLIN)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let ready = loop {
+            match poll(&mut fds, timeout) {
+                Err(::nix::Error::Sys(Errno::EINTR)) => continue,
+                res => {
+                    break res.map_err(|inner| Error::Posix {
+                        call: "poll",
+                        inner,
+                    })?
+                }
+            }
+        };
+
+        for (fd, hung_up) in fds.iter().zip(&mut self.hung_up) {
+            if let Some(revents) = fd.revents() {
+                if revents.intersects(EventFlags::POLLHUP | EventFlags::POLLERR) {
+                    debug!("a ring buffer's events have hung up");
+                    *hung_up = true;
+                }
+            }
+        }
+
+        Ok(ready > 0)
+    }
+
+    /// Passes every record which is currently in the buffers to `f` without blocking, returning
+    /// how many there were.
+    pub fn drain(&mut self, mut f: impl FnMut(Record)) -> Result<usize> {
+        let mut count = 0;
+        while let Some(record) = self.try_next() {
+            f(record?);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Reads the next record from whichever buffer is due, if any buffer has one.
+    fn try_next(&mut self) -> Option<Result<Record>> {
+        for _ in 0..self.buffers.len() {
+            let index = self.next_buffer;
+            self.next_buffer = (self.next_buffer + 1) % self.buffers.len();
+
+            let buffer = &mut self.buffers[index];
+            let mut record = match buffer.next() {
+                Some(Ok(record)) => record,
+                Some(Err(why)) => return Some(Err(why)),
+                None => continue,
+            };
+
+            record.source = record.source.map(|source| source + self.offsets[index]);
+            self.stats.count(&record);
+            self.stats.bytes_read = self.buffers.iter().map(|b| b.bytes_read() as u64).sum();
+            return Some(Ok(record));
+        }
+
+        None
+    }
+}
+
+/// Converts a timeout to poll(2)'s milliseconds, rounding up so that a timeout of less than a
+/// millisecond doesn't become 0 and make callers spin.
+fn poll_timeout(timeout: Option<Duration>) -> i32 {
+    match timeout {
+        Some(t) => {
+            let millis = t.as_nanos().div_ceil(1_000_000);
+            millis.min(i32::MAX as u128) as i32
+        }
+        None => -1,
+    }
+}
+
+/// Blocks waiting for each record, ending once every buffer's events have hung up and the
+/// buffers have been drained.
+impl Iterator for BlockingSampler {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.try_next() {
+                return Some(record);
+            }
+
+            if self.hung_up.iter().all(|&h| h) {
+                return None;
+            }
+
+            if let Err(why) = self.wait(None) {
+                return Some(Err(why));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sample::config::SamplingRate;
+    use sample::record::RecordContents;
+    use {Counted, SwEvent};
+
+    #[test]
+    fn wait_and_drain() {
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+        let mut sampler =
+            BlockingSampler::with_buffers(vec![vec![config.clone()], vec![config]]).unwrap();
+
+        let mut samples = 0;
+        let mut sources = Vec::new();
+        while samples < 10 {
+            // burn some cpu on this thread so the task clock has something to sample
+            ::test::burn_cpu();
+
+            if !sampler.wait(Some(Duration::from_millis(100))).unwrap() {
+                continue;
+            }
+
+            sampler
+                .drain(|record| {
+                    if let RecordContents::Sample(_) = record.contents {
+                        samples += 1;
+                        sources.push(record.source);
+                    }
+                })
+                .unwrap();
+        }
+
+        assert_eq!(sampler.stats().samples, samples);
+        assert!(sources.contains(&Some(0)));
+        assert!(sources.contains(&Some(1)));
+    }
+
+    #[test]
+    fn waiting_after_hanging_up() {
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+        let mut sampler = BlockingSampler::new(vec![config]).unwrap();
+        sampler.hung_up = vec![true];
+        assert!(!sampler.wait(None).unwrap());
+
+        assert_eq!(poll_timeout(None), -1);
+        assert_eq!(poll_timeout(Some(Duration::from_micros(500))), 1);
+        assert_eq!(poll_timeout(Some(Duration::from_millis(2))), 2);
+        assert_eq!(poll_timeout(Some(Duration::from_secs(1 << 40))), i32::MAX);
+    }
+
+    #[test]
+    fn cgroup_buffer_per_cpu() {
+        let config = SamplingConfig {
+            shared: ::EventConfig {
+                pid: PidConfig::Cgroup(::test::own_cgroup()),
+                ..::EventConfig::default()
+            },
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+        let mut sampler = BlockingSampler::new(vec![config]).unwrap();
+        assert_eq!(sampler.buffers.len(), online_cpus().unwrap().len());
+
+        let mut samples = 0;
+        while samples == 0 {
+            ::test::burn_cpu();
+
+            sampler.wait(Some(Duration::from_millis(100))).unwrap();
+            sampler
+                .drain(|record| {
+                    if let RecordContents::Sample(_) = record.contents {
+                        assert_eq!(record.source, Some(0));
+                        samples += 1;
+                    }
+                })
+                .unwrap();
+        }
+    }
+}
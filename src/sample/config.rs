@@ -1,11 +1,20 @@
-use super::EventConfig;
+use page_size::get as page_size;
+
+use super::{ring_buffer::BufferSizeError, signal::SignalTarget, EventConfig};
+use count::{Counted, HwEvent, SwEvent};
+use fallback::Fallback;
 use fd::PerfEventAttrThingy;
 use raw::perf_event_attr;
 use {CpuConfig, PidConfig};
 
-#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SamplingConfig {
     pub shared: EventConfig,
+    /// The event whose overflows generate samples. A `SwEvent::DummyForSampled` event can be used
+    /// to collect only the informational records (mmap, comm, etc.) alongside other events which
+    /// share its ring buffer.
+    pub event: Counted,
     pub rate: SamplingRate,
     pub requests: Vec<SampleRequest>,
     /// If set, then TID, TIME, ID, STREAM_ID, and CPU can additionally be included in
@@ -15,6 +24,12 @@ pub struct SamplingConfig {
     /// value to ease parsing the record stream. This may lead to the id value appearing twice.
     pub sample_id_all: bool,
     pub wakeup: WakeupConfig,
+    /// How much memory to map for the ring buffer which samples are written to. When several
+    /// events share one ring buffer, the size of the first event's buffer is used.
+    pub buffer_size: BufferSize,
+    /// If set, the kernel delivers a signal to a thread each time it wakes up readers of the ring
+    /// buffer (see `wakeup`), in addition to the file descriptor polling readable.
+    pub signal: Option<SignalTarget>,
     //    sample_regs_user (since Linux 3.7)
     //           This bit mask defines the set of user CPU registers to dump on
     //           samples.  The layout of the register mask is architecture-spe‐
@@ -30,13 +45,38 @@ pub struct SamplingConfig {
     //           When sample_type includes PERF_SAMPLE_CALLCHAIN, this field
     //           specifies how many stack frames to report when generating the
     //           callchain.
-    mmap: bool,
-    comm: bool,
-    enable_on_exec: bool,
-    task: bool,
-    precise_ip: u16,
-    mmap2: bool,
-    comm_exec: bool,
+    /// Enables generation of PERF_RECORD_MMAP records for every mmap(2) call that has PROT_EXEC
+    /// set. This allows tools to notice new executable code being mapped into a program (dynamic
+    /// shared libraries for example) so that addresses can be mapped back to the original code.
+    pub mmap: bool,
+    /// Enables tracking of process command name as modified by the exec(2) and prctl(PR_SET_NAME)
+    /// system calls as well as writing to /proc/self/comm.
+    pub comm: bool,
+    /// If set, the counter is automatically enabled after a call to exec(2).
+    pub enable_on_exec: bool,
+    /// If set, then fork/exit notifications are included in the ring buffer.
+    pub task: bool,
+    /// This controls the amount of skid. Skid is how many instructions execute between an event
+    /// of interest happening and the kernel being able to stop and record the event. Smaller skid
+    /// is better, but may not be supported by the hardware:
+    ///
+    /// * 0 - SAMPLE_IP can have arbitrary skid.
+    /// * 1 - SAMPLE_IP must have constant skid.
+    /// * 2 - SAMPLE_IP requested to have 0 skid.
+    /// * 3 - SAMPLE_IP must have 0 skid.
+    pub precise_ip: u16,
+    /// Generate an extended executable mmap record that contains enough additional information to
+    /// uniquely identify shared mappings. The mmap flag must also be set for this to work. (since
+    /// Linux 3.16)
+    pub mmap2: bool,
+    /// This is purely a feature-detection flag, it does not change kernel behavior. If set, the
+    /// PERF_RECORD_MISC_COMM_EXEC flag is set on comm records caused by exec(2). (since Linux
+    /// 3.16)
+    pub comm_exec: bool,
+    /// What to try if the kernel or hardware rejects the event. By default a too-precise
+    /// `precise_ip` is lowered, since most virtual machines don't support precise sampling, and
+    /// then the cpu-clock software event is sampled on machines without a PMU.
+    pub fallbacks: Vec<Fallback>,
 }
 
 impl AsRef<CpuConfig> for SamplingConfig {
@@ -63,9 +103,12 @@ impl ::std::default::Default for SamplingConfig {
     fn default() -> Self {
         SamplingConfig {
             shared: EventConfig::default(),
+            event: Counted::Hardware(HwEvent::CpuCycles),
             requests: vec![SampleRequest::InstructionPointer, SampleRequest::Period],
             rate: SamplingRate::Frequency(4000),
             wakeup: WakeupConfig::NumSamples(1),
+            buffer_size: BufferSize::default(),
+            signal: None,
             sample_id_all: true,
             mmap: true,
             comm: true,
@@ -74,14 +117,17 @@ impl ::std::default::Default for SamplingConfig {
             precise_ip: 3,
             mmap2: true,
             comm_exec: true,
+            fallbacks: vec![
+                Fallback::LowerPrecision,
+                Fallback::Event(Counted::Software(SwEvent::CpuClock)),
+            ],
         }
     }
 }
 
 impl PerfEventAttrThingy for SamplingConfig {
     fn apply(&self, attr: &mut perf_event_attr) {
-        attr.type_ = ::raw::perf_type_id::PERF_TYPE_HARDWARE;
-        attr.config = ::count::HwEvent::CpuCycles as u64;
+        self.event.apply(attr);
 
         self.rate.apply(attr);
         self.wakeup.apply(attr);
@@ -109,7 +155,7 @@ impl Display for SamplingConfig {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum SamplingRate {
     /// A "sampling" event is one that generates an overflow notification every N events, where N is
     /// given by sample_period.  A sampling event has sample_period > 0.  When an overflow occurs,
@@ -149,7 +195,7 @@ impl SamplingRate {
 //           (wakeup_watermark) happen before an overflow notification hap‐
 //           pens.  Which one is used is selected by the watermark bit
 //           flag.
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum WakeupConfig {
     NumSamples(u32),
     WatermarkBytes(u32),
@@ -171,17 +217,91 @@ impl WakeupConfig {
     }
 }
 
+/// The size of the data section of a ring buffer, which doesn't include the extra metadata page
+/// mapped ahead of it. The kernel requires the data section to be a power of two number of pages.
+///
+/// Every buffer counts against the perf_event_mlock_kb limit (see `BufferSizeError`), and a
+/// buffer which is too small for the sampling rate will drop records while it's full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum BufferSize {
+    /// An exact number of pages, which must be a power of two.
+    Pages(usize),
+    /// At least this many bytes, rounded up to the next power of two number of pages.
+    Bytes(usize),
+}
+
+impl BufferSize {
+    /// 128 pages, 512KiB with 4KiB pages.
+    pub const DEFAULT_PAGES: usize = 128;
+
+    /// Returns the number of data pages to map, rounding `Bytes` up and rejecting `Pages` which
+    /// the kernel would refuse to map.
+    pub fn data_pages(&self) -> Result<usize, BufferSizeError> {
+        let pages = match *self {
+            BufferSize::Pages(pages) => {
+                if !pages.is_power_of_two() {
+                    return Err(BufferSizeError::NotPowerOfTwo { pages });
+                }
+                pages
+            }
+            BufferSize::Bytes(0) => return Err(BufferSizeError::Empty),
+            BufferSize::Bytes(bytes) => {
+                let page_size = page_size();
+                let pages = (bytes / page_size) + (bytes % page_size != 0) as usize;
+                pages
+                    .checked_next_power_of_two()
+                    .ok_or(BufferSizeError::TooLarge { bytes })?
+            }
+        };
+
+        // the metadata page is mapped too, make sure the whole length is addressable
+        match (pages + 1).checked_mul(page_size()) {
+            Some(_) => Ok(pages),
+            None => Err(BufferSizeError::TooLarge {
+                bytes: pages.saturating_mul(page_size()),
+            }),
+        }
+    }
+}
+
+impl Default for BufferSize {
+    fn default() -> Self {
+        BufferSize::Pages(Self::DEFAULT_PAGES)
+    }
+}
+
 /// Specifies which values to include in the sample. They will be recorded in a ring-buffer, which
 /// is available to user space using mmap(2). The order in which the values are saved in the sample
 /// are documented in the MMAP Layout subsection; it is not the enum perf_event_sample_format order.
-#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum SampleRequest {
+    /// Places the SAMPLE_ID value in a fixed position in the record, which allows records from
+    /// several events sharing one ring buffer to be attributed to the event which wrote them.
+    /// (since Linux 3.12)
+    Identifier,
+
     /// Records instruction pointer.
     InstructionPointer,
 
+    /// Records the process and thread IDs.
+    ThreadId,
+
+    /// Records a timestamp.
+    Time,
+
     /// Records an address, if applicable.
     Address,
 
+    /// Records a unique ID for the opened event's group leader.
+    Id,
+
+    /// Records a unique ID for the opened event. Unlike `Id` the actual ID is returned, not the
+    /// group leader.
+    StreamId,
+
+    /// Records CPU number.
+    Cpu,
+
     /// Record counter values for all events in a group, not just the group leader.
     Read,
 
@@ -241,8 +361,14 @@ impl SampleRequest {
         use self::SampleRequest::*;
         use raw::perf_event_sample_format::*;
         attr.sample_type |= match *self {
+            Identifier => PERF_SAMPLE_IDENTIFIER,
             InstructionPointer => PERF_SAMPLE_IP,
+            ThreadId => PERF_SAMPLE_TID,
+            Time => PERF_SAMPLE_TIME,
             Address => PERF_SAMPLE_ADDR,
+            Id => PERF_SAMPLE_ID,
+            StreamId => PERF_SAMPLE_STREAM_ID,
+            Cpu => PERF_SAMPLE_CPU,
             Read => PERF_SAMPLE_READ,
             Callchain => PERF_SAMPLE_CALLCHAIN,
             Period => PERF_SAMPLE_PERIOD,
@@ -268,7 +394,6 @@ use raw::perf_branch_sample_type::*;
 bitflags! {
     /// If SamplingType::BRANCH_STACK is enabled, then this specifies what branches to include in
     /// the branch record. els do not have to match. (since Linux 3.4)
-    #[derive(Serialize)]
     pub struct BranchSamplePriv: u32 {
         /// Branch target is in user space.
         const USER = PERF_SAMPLE_BRANCH_USER;
@@ -286,7 +411,6 @@ bitflags! {
 
 bitflags! {
     /// In addition to the privilege value, at least one or more of the following bits must be set.
-    #[derive(Serialize)]
     pub struct BranchSampleType: u32 {
         /// Any branch type.
         const ANY = PERF_SAMPLE_BRANCH_ANY;
@@ -324,6 +448,27 @@ bitflags! {
     }
 }
 
+serde_flag_names!(BranchSamplePriv {
+    USER,
+    KERNEL,
+    HV,
+    ALL
+});
+
+serde_flag_names!(BranchSampleType {
+    ANY,
+    ANY_CALL,
+    IND_CALL,
+    CALL,
+    ANY_RETURN,
+    IND_JUMP,
+    COND,
+    ABORT_TX,
+    IN_TX,
+    NO_TX,
+    CALL_STACK,
+});
+
 pub type AllSampleIds = SampleId<Tid, Time, Id, StreamId, Cpu, Identifier>;
 pub type NoSampleIds = SampleId<(), (), (), (), (), ()>;
 
@@ -435,4 +580,27 @@ mod tests {
 
         // assert_eq!(attr1, attr2);
     }
+
+    #[test]
+    fn buffer_sizes() {
+        let page = page_size();
+
+        assert_eq!(BufferSize::default().data_pages().unwrap(), 128);
+        assert_eq!(BufferSize::Pages(1).data_pages().unwrap(), 1);
+        assert_eq!(BufferSize::Bytes(1).data_pages().unwrap(), 1);
+        assert_eq!(BufferSize::Bytes(page).data_pages().unwrap(), 1);
+        assert_eq!(BufferSize::Bytes(page * 3).data_pages().unwrap(), 4);
+        assert_eq!(BufferSize::Bytes(page * 4 + 1).data_pages().unwrap(), 8);
+
+        assert_eq!(
+            BufferSize::Pages(0).data_pages(),
+            Err(BufferSizeError::NotPowerOfTwo { pages: 0 })
+        );
+        assert_eq!(
+            BufferSize::Pages(3).data_pages(),
+            Err(BufferSizeError::NotPowerOfTwo { pages: 3 })
+        );
+        assert_eq!(BufferSize::Bytes(0).data_pages(), Err(BufferSizeError::Empty));
+        assert!(BufferSize::Bytes(!0).data_pages().is_err());
+    }
 }
@@ -1,26 +1,66 @@
+pub mod blocking;
 pub mod config;
 pub mod record;
 pub mod ring_buffer;
+pub mod signal;
+#[cfg(feature = "async")]
+pub mod stream;
 
-use std::thread::{spawn, JoinHandle};
+use std::{
+    sync::{Arc, Mutex},
+    thread::{spawn, JoinHandle},
+    time::Duration,
+};
 
 use self::{
     config::SamplingConfig,
-    record::{Decoder, Record},
+    record::{Decoder, Record, RecordContents},
     ring_buffer::RingBuffer,
 };
 use super::EventConfig;
 use error::*;
+use fallback::Opened;
+use fd::AttrExtension;
+use self_monitor::PerfClock;
 
 /// Launch the sampler on a separate thread, returning a handle from which sampled events can
 /// be collected.
 pub fn sampler(sample_config: SamplingConfig) -> Result<SamplerHandle> {
+    sampler_group(vec![sample_config])
+}
+
+/// Launch a sampler for several events which all write to the same ring buffer, e.g. cycles plus
+/// a dummy event collecting mmap and comm records. Each `Record`'s `source` is the index of the
+/// config which produced it.
+pub fn sampler_group(sample_configs: Vec<SamplingConfig>) -> Result<SamplerHandle> {
+    sampler_group_with_backlog(sample_configs, Backlog::Unbounded)
+}
+
+/// Launch a sampler for several events which all write to the same ring buffer, holding records
+/// which haven't been received from the `SamplerHandle` yet according to `backlog`.
+pub fn sampler_group_with_backlog(
+    sample_configs: Vec<SamplingConfig>,
+    backlog: Backlog,
+) -> Result<SamplerHandle> {
+    // open the events here so that PidConfig::Current refers to the caller, not the sampler thread
+    let buffer = RingBuffer::new(sample_configs)?;
+
     debug!("enabling our ring buffer's file descriptor");
+    buffer.enable_fd()?;
+    let clock = buffer.clock();
+    let dropped = buffer.dropped();
+    let opened = buffer.opened().to_vec();
 
     // three channels: a shutdown channel, a results channel, and an error channel
     let (stop, shutdown): (StopSender, StopReceiver) = ::futures::sync::oneshot::channel();
-    let (record_sender, records) = channel::unbounded();
+    let (record_sender, records) = match backlog {
+        Backlog::Unbounded => channel::unbounded(),
+        Backlog::Bounded { capacity, .. } => channel::bounded(capacity),
+    };
+    let record_sender = BacklogSender::new(backlog, record_sender, records.clone());
     let (error_sender, error) = channel::bounded(1);
+    let stats = Arc::new(Mutex::new(SessionStats::default()));
+    let decoder_stats = stats.clone();
 
     let outer_error_sender = error_sender.clone();
 
@@ -37,12 +77,10 @@ pub fn sampler(sample_config: SamplingConfig) -> Result<SamplerHandle> {
             let mut rt = Runtime::new()?;
             rt.spawn(empty()); // start the runtime
 
-            let buffer = RingBuffer::new(sample_config)?;
-            buffer.enable_fd()?;
-
             // we want to keep running the sampler in the background on this thread
             debug!("spawning decoder");
-            rt.spawn(Decoder::new(buffer, record_sender, error_sender).for_each(|()| ok(())));
+            rt.spawn(Decoder::new(buffer, record_sender, error_sender, decoder_stats)
+                .for_each(|()| ok(())));
 
             // this runs the executor until the shutdown channel has a value
             debug!("running executor until shutdown message received");
@@ -63,9 +101,56 @@ pub fn sampler(sample_config: SamplingConfig) -> Result<SamplerHandle> {
         records,
         error,
         sampler,
+        stats,
+        clock,
+        dropped,
+        opened,
     })
 }
 
+/// Start sampling into a buffer which the kernel continuously overwrites, keeping only the most
+/// recent records. Nothing is read until `FlightRecorder::snapshot` is called, so the cost of
+/// leaving this running is only the kernel's cost of writing samples.
+pub fn flight_recorder(sample_configs: Vec<SamplingConfig>) -> Result<FlightRecorder> {
+    let buffer = RingBuffer::overwritable(sample_configs)?;
+
+    debug!("enabling our ring buffer's file descriptor");
+    buffer.enable_fd()?;
+
+    Ok(FlightRecorder { buffer })
+}
+
+/// An always-on sampler whose ring buffer holds the most recent records, e.g. to dump on a signal
+/// or after a latency spike.
+pub struct FlightRecorder {
+    buffer: RingBuffer,
+}
+
+impl FlightRecorder {
+    /// Pauses the kernel's output, decodes the records currently in the buffer (oldest first), and
+    /// resumes output. Samples which occur while the snapshot is being taken are lost.
+    pub fn snapshot(&self) -> Result<Vec<Record>> {
+        self.buffer.snapshot()
+    }
+
+    /// Returns the parameters for converting between the hardware clock and the timestamps in
+    /// the recorded samples, if the hardware has a constant and nonstop clock.
+    pub fn clock(&self) -> Option<PerfClock> {
+        self.buffer.clock()
+    }
+
+    /// Returns the fields of the configs which this kernel doesn't support, and which were
+    /// dropped to open the events.
+    pub fn dropped_fields(&self) -> Vec<AttrExtension> {
+        self.buffer.dropped()
+    }
+
+    /// Returns what was opened for each config, after any of their fallbacks.
+    pub fn opened(&self) -> &[Opened] {
+        self.buffer.opened()
+    }
+}
+
 pub fn sampled<R>(
     sample_config: SamplingConfig,
     f: impl FnOnce() -> R,
@@ -85,7 +170,105 @@ pub fn sampled<R>(
     }
 }
 
-use channel::{self, Receiver};
+use channel::{self, Receiver, Sender};
+
+/// How many decoded records are held for the consumer of a `SamplerHandle`, and what to do when
+/// the consumer falls behind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backlog {
+    /// Hold every record until it's received, or until the sampler is joined.
+    Unbounded,
+    /// Hold at most `capacity` records, applying `policy` to any which arrive while it's full.
+    Bounded { capacity: usize, policy: DropPolicy },
+}
+
+/// What a sampler does with a record when its `Backlog` is full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DropPolicy {
+    /// Stop reading from the ring buffer until there's room. Once the ring buffer fills up the
+    /// kernel drops records instead, which are counted in `SessionStats::lost`.
+    Block,
+    /// Discard the new record, counting it in `SessionStats::dropped`.
+    DropNewest,
+    /// Discard the oldest held record to make room for the new one, counting it in
+    /// `SessionStats::dropped`.
+    DropOldest,
+}
+
+/// The outcome of handing a record to a `BacklogSender`.
+pub(crate) enum Offer {
+    Sent,
+    /// The record was sent but another had to be discarded to make room for it, or the record
+    /// was discarded itself.
+    Dropped,
+    /// The record can't be sent until the consumer catches up.
+    Full(Record),
+}
+
+pub(crate) struct BacklogSender {
+    sender: Sender<Record>,
+    /// Used to discard the oldest record under `DropPolicy::DropOldest`.
+    receiver: Receiver<Record>,
+    policy: Option<DropPolicy>,
+}
+
+impl BacklogSender {
+    fn new(backlog: Backlog, sender: Sender<Record>, receiver: Receiver<Record>) -> Self {
+        let policy = match backlog {
+            Backlog::Unbounded => None,
+            Backlog::Bounded { policy, .. } => Some(policy),
+        };
+
+        Self {
+            sender,
+            receiver,
+            policy,
+        }
+    }
+
+    pub(crate) fn offer(&self, record: Record) -> Offer {
+        let policy = match self.policy {
+            Some(p) => p,
+            None => {
+                self.sender.send(record);
+                return Offer::Sent;
+            }
+        };
+
+        let mut record = Some(record);
+        let mut discarded = false;
+        loop {
+            match policy {
+                // wait a little while so that the sampler thread doesn't spin, but can still
+                // notice when it's been asked to stop
+                DropPolicy::Block => select! {
+                    send(self.sender, record.take().unwrap()) => (),
+                    recv(channel::after(Duration::from_millis(10))) => (),
+                },
+                _ => select! {
+                    send(self.sender, record.take().unwrap()) => (),
+                    default => (),
+                },
+            }
+
+            match (record.take(), policy) {
+                (None, _) if discarded => return Offer::Dropped,
+                (None, _) => return Offer::Sent,
+                (Some(r), DropPolicy::Block) => return Offer::Full(r),
+                (Some(_), DropPolicy::DropNewest) => return Offer::Dropped,
+                (Some(r), DropPolicy::DropOldest) => {
+                    // there's nothing to discard if the consumer emptied the channel in the
+                    // meantime, or if it can't hold any records at all
+                    if self.receiver.try_recv().is_none() {
+                        return Offer::Dropped;
+                    }
+                    discarded = true;
+                    record = Some(r);
+                }
+            }
+        }
+    }
+}
 
 pub struct StopSampling;
 type StopSender = ::futures::sync::oneshot::Sender<StopSampling>;
@@ -96,17 +279,82 @@ pub struct SamplerHandle {
     records: Receiver<Record>,
     error: Receiver<Error>,
     sampler: JoinHandle<()>,
+    stats: Arc<Mutex<SessionStats>>,
+    clock: Option<PerfClock>,
+    dropped: Vec<AttrExtension>,
+    opened: Vec<Opened>,
 }
 
 impl SamplerHandle {
+    /// Returns the parameters for converting between the hardware clock and the timestamps in
+    /// samples, as they were when the sampler started.
+    pub fn clock(&self) -> Option<PerfClock> {
+        self.clock
+    }
+
+    /// Returns the statistics for the records which have been read from the ring buffer so far.
+    pub fn stats(&self) -> SessionStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Returns the fields of the configs which this kernel doesn't support, and which were
+    /// dropped to open the events, e.g. `Clockid` before Linux 4.1.
+    pub fn dropped_fields(&self) -> &[AttrExtension] {
+        &self.dropped
+    }
+
+    /// Returns what was opened for each config, after any of their fallbacks.
+    pub fn opened(&self) -> &[Opened] {
+        &self.opened
+    }
+
+    /// Returns an iterator over records as they're read from the ring buffer, which blocks
+    /// waiting for each record and ends once the sampler has stopped and every record has been
+    /// received. Records received here aren't returned when the sampler is joined.
+    pub fn records(&self) -> Records {
+        Records {
+            records: self.records.clone(),
+        }
+    }
+
+    /// Receives a record which has already been read from the ring buffer, if there is one.
+    /// Returns `TryRecvError::Disconnected` once the sampler has stopped and every record has been
+    /// received.
+    pub fn try_recv(&self) -> ::std::result::Result<Record, TryRecvError> {
+        select! {
+            recv(self.records, record) => record.ok_or(TryRecvError::Disconnected),
+            default => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Waits up to `timeout` for a record to be read from the ring buffer. Returns
+    /// `RecvTimeoutError::Disconnected` once the sampler has stopped and every record has been
+    /// received.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> ::std::result::Result<Record, RecvTimeoutError> {
+        select! {
+            recv(self.records, record) => record.ok_or(RecvTimeoutError::Disconnected),
+            recv(channel::after(timeout)) => Err(RecvTimeoutError::Timeout),
+        }
+    }
+
     pub fn join_with_remaining(self) -> Result<Vec<Record>> {
+        self.join_with_stats().map(|(records, _)| records)
+    }
+
+    /// Stops the sampler and returns the remaining records along with the statistics for the
+    /// whole session, which should be checked with `SessionStats::is_complete` before trusting
+    /// a profile built from the records.
+    pub fn join_with_stats(self) -> Result<(Vec<Record>, SessionStats)> {
         debug!("sending stop signal to sampler thread");
         let _its_ok_if_we_already_sent_one = self.stop.send(StopSampling);
 
         debug!("joining on sampler thread");
         if let Err(why) = self.sampler.join() {
             return Err(Error::Misc {
-                inner: ::failure::err_msg(format!("sampler thread panicked: {:?}", why)),
+                inner: format!("sampler thread panicked: {:?}", why),
             });
         }
 
@@ -117,7 +365,88 @@ impl SamplerHandle {
 
         debug!("no errors reported from sampler thread.");
 
-        Ok(self.records.into_iter().collect())
+        let stats = *self.stats.lock().unwrap();
+        if !stats.is_complete() {
+            warn!("sampling session was incomplete: {:?}", stats);
+        }
+
+        Ok((self.records.into_iter().collect(), stats))
+    }
+}
+
+/// A blocking iterator over the records read by a running sampler, see `SamplerHandle::records`.
+pub struct Records {
+    records: Receiver<Record>,
+}
+
+impl Iterator for Records {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        self.records.recv()
+    }
+}
+
+/// Why `SamplerHandle::try_recv` didn't return a record.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum TryRecvError {
+    #[error("no records have been read from the ring buffer")]
+    Empty,
+    #[error("the sampler has stopped and every record has been received")]
+    Disconnected,
+}
+
+/// Why `SamplerHandle::recv_timeout` didn't return a record.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum RecvTimeoutError {
+    #[error("no records were read from the ring buffer before the timeout")]
+    Timeout,
+    #[error("the sampler has stopped and every record has been received")]
+    Disconnected,
+}
+
+/// Counts of what the kernel wrote to a sampler's ring buffer, including the records it had to
+/// drop.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SessionStats {
+    /// PERF_RECORD_SAMPLEs read from the buffer.
+    pub samples: u64,
+    /// Records of any kind read from the buffer.
+    pub records: u64,
+    /// Records the kernel dropped because the buffer was full, as reported by PERF_RECORD_LOST.
+    pub lost: u64,
+    /// Samples the kernel dropped when using hardware sampling such as Intel PEBS, as reported by
+    /// PERF_RECORD_LOST_SAMPLES. (since Linux 4.2)
+    pub lost_samples: u64,
+    /// How many times an event was throttled because it was interrupting too frequently, as
+    /// reported by PERF_RECORD_THROTTLE. No samples are taken while an event is throttled.
+    pub throttled: u64,
+    /// How many times a throttled event resumed sampling, as reported by PERF_RECORD_UNTHROTTLE.
+    pub unthrottled: u64,
+    /// Bytes read from the ring buffer's data section.
+    pub bytes_read: u64,
+    /// Records which were read from the ring buffer but discarded because the sampler's
+    /// `Backlog` was full.
+    pub dropped: u64,
+}
+
+impl SessionStats {
+    /// Returns true if no samples were lost, dropped or throttled, i.e. a profile built from the
+    /// session's records isn't missing data.
+    pub fn is_complete(&self) -> bool {
+        self.lost == 0 && self.lost_samples == 0 && self.throttled == 0 && self.dropped == 0
+    }
+
+    pub(crate) fn count(&mut self, record: &Record) {
+        self.records += 1;
+        match record.contents {
+            RecordContents::Sample(_) => self.samples += 1,
+            RecordContents::Lost { lost, .. } => self.lost += lost,
+            RecordContents::LostSamples { lost } => self.lost_samples += lost,
+            RecordContents::Throttle(_) => self.throttled += 1,
+            RecordContents::Unthrottle(_) => self.unthrottled += 1,
+            _ => (),
+        }
     }
 }
 
@@ -153,4 +482,202 @@ mod tests {
         }).unwrap();
         assert_ne!(samples.len(), 0);
     }
+
+    #[test]
+    fn shared_buffer() {
+        use sample::record::RecordContents;
+        use {Counted, SwEvent};
+
+        let _ = ::env_logger::Builder::from_default_env()
+            .filter(None, ::log::LevelFilter::Debug)
+            .filter(Some("tokio_reactor"), ::log::LevelFilter::Info)
+            .try_init();
+
+        let clock = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            mmap: false,
+            mmap2: false,
+            comm: false,
+            task: false,
+            ..SamplingConfig::default()
+        };
+
+        let tracking = SamplingConfig {
+            event: Counted::Software(SwEvent::DummyForSampled),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+
+        let handle = sampler_group(vec![clock, tracking]).unwrap();
+
+        for _ in 0..10 {
+            ::test::burn_cpu();
+        }
+
+        // mapping and naming something new should show up as tracking records on the dummy
+        ::std::thread::spawn(|| ()).join().unwrap();
+
+        let (records, stats) = handle.join_with_stats().unwrap();
+
+        let mut samples = 0;
+        for record in &records {
+            match record.contents {
+                RecordContents::Sample(_) => {
+                    assert_eq!(record.source, Some(0));
+                    samples += 1;
+                }
+                RecordContents::Comm { .. }
+                | RecordContents::Mmap { .. }
+                | RecordContents::Mmap2 { .. }
+                | RecordContents::Fork(_)
+                | RecordContents::Exit(_) => assert_eq!(record.source, Some(1)),
+                _ => assert!(record.source.is_some()),
+            }
+        }
+
+        assert_ne!(samples, 0);
+        assert_eq!(stats.samples, samples);
+        assert_eq!(stats.records, records.len() as u64);
+        assert!(stats.bytes_read > 0);
+    }
+
+    #[test]
+    fn live_records() {
+        use std::time::Duration;
+        use {Counted, SwEvent};
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+
+        let handle = sampler(config).unwrap();
+
+        let mut received = 0;
+        while received < 10 {
+            ::test::burn_cpu();
+
+            if handle.recv_timeout(Duration::from_millis(100)).is_ok() {
+                received += 1;
+            }
+        }
+
+        handle.join_with_remaining().unwrap();
+    }
+
+    #[test]
+    fn bounded_backlog_drops_newest() {
+        use {Counted, SwEvent};
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+
+        let backlog = Backlog::Bounded {
+            capacity: 4,
+            policy: DropPolicy::DropNewest,
+        };
+        let handle = sampler_group_with_backlog(vec![config], backlog).unwrap();
+
+        for _ in 0..10 {
+            ::test::burn_cpu();
+        }
+
+        let (records, stats) = handle.join_with_stats().unwrap();
+        assert!(records.len() <= 4);
+        assert_ne!(stats.dropped, 0);
+        assert_eq!(stats.records, records.len() as u64 + stats.dropped);
+        assert!(!stats.is_complete());
+    }
+
+    #[test]
+    fn samplers_release_their_resources() {
+        use super::config::BufferSize;
+        use std::fs::{read_dir, read_link, read_to_string};
+        use {Counted, SwEvent};
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            buffer_size: BufferSize::Pages(1),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+
+        for _ in 0..2_000 {
+            let handle = sampler(config.clone()).unwrap();
+            handle.join_with_remaining().unwrap();
+        }
+
+        // other tests may be sampling at the same time, but a leak would leave thousands behind
+        let mappings = read_to_string("/proc/self/maps")
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains("perf_event"))
+            .count();
+        assert!(mappings < 100, "{} perf_event mappings remain", mappings);
+
+        let files = read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| read_link(entry.unwrap().path()).ok())
+            .filter(|target| target.to_string_lossy().contains("perf_event"))
+            .count();
+        assert!(files < 100, "{} perf_event files remain open", files);
+    }
+
+    #[test]
+    fn flight_recorder_keeps_recent_samples() {
+        use sample::record::RecordContents;
+        use {Counted, SwEvent};
+
+        let _ = ::env_logger::Builder::from_default_env()
+            .filter(None, ::log::LevelFilter::Info)
+            .try_init();
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(10_000),
+            requests: vec![SampleRequest::Time],
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+
+        let recorder = flight_recorder(vec![config]).unwrap();
+
+        for _ in 0..10 {
+            ::test::burn_cpu();
+        }
+
+        fn sample_times(records: &[Record]) -> Vec<u64> {
+            records
+                .iter()
+                .filter_map(|r| match r.contents {
+                    RecordContents::Sample(ref s) => s.time,
+                    _ => None,
+                })
+                .collect()
+        }
+
+        let first = sample_times(&recorder.snapshot().unwrap());
+        assert_ne!(first.len(), 0);
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(first, sorted);
+
+        // the recorder keeps running after a snapshot
+        for _ in 0..10 {
+            ::test::burn_cpu();
+        }
+
+        let second = sample_times(&recorder.snapshot().unwrap());
+        assert!(second.last() > first.last());
+    }
 }
@@ -1,27 +1,38 @@
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
 use channel::Sender;
-use futures::{Async, Stream};
+use futures::{task, Async, Stream};
 use num::FromPrimitive;
 
 use error::{Error, Result};
 use raw::*;
-use sample::ring_buffer::RingBuffer;
+use sample::{ring_buffer::RingBuffer, BacklogSender, Offer, SessionStats};
 
 pub struct Decoder {
     buffer: RingBuffer,
     error_channel: Sender<Error>,
-    record_channel: Sender<Record>,
+    record_channel: BacklogSender,
+    stats: Arc<Mutex<SessionStats>>,
+    /// A record which has been read but is waiting for room in the record channel.
+    pending: Option<Record>,
 }
 
 impl Decoder {
     pub(crate) fn new(
         buffer: RingBuffer,
-        record_channel: Sender<Record>,
+        record_channel: BacklogSender,
         error_channel: Sender<Error>,
+        stats: Arc<Mutex<SessionStats>>,
     ) -> Self {
         Self {
             buffer,
             record_channel,
             error_channel,
+            stats,
+            pending: None,
         }
     }
 }
@@ -31,57 +42,613 @@ impl Stream for Decoder {
     type Error = ();
 
     fn poll(&mut self) -> ::std::result::Result<Async<Option<Self::Item>>, Self::Error> {
-        trace!("polling decoder buffer");
-        match self.buffer.poll() {
-            Ok(Async::Ready(Some(record))) => {
-                info!("buffer had a record ready");
-                self.record_channel.send(record);
+        let record = match self.pending.take() {
+            Some(record) => record,
+            None => {
+                trace!("polling decoder buffer");
+                match self.buffer.poll() {
+                    Ok(Async::Ready(Some(record))) => {
+                        info!("buffer had a record ready");
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.count(&record);
+                        stats.bytes_read = self.buffer.bytes_read() as u64;
+                        record
+                    }
+                    Err(why) => {
+                        error!("problem reading fd: {:?}", why);
+                        self.error_channel.send(why);
+                        return Err(());
+                    }
+                    _ => return Ok(Async::NotReady),
+                }
+            }
+        };
+
+        match self.record_channel.offer(record) {
+            Offer::Sent => Ok(Async::Ready(Some(()))),
+            Offer::Dropped => {
+                self.stats.lock().unwrap().dropped += 1;
                 Ok(Async::Ready(Some(())))
             }
-            Err(why) => {
-                error!("problem reading fd: {:?}", why);
-                self.error_channel.send(why);
-                Err(())
+            Offer::Full(record) => {
+                trace!("record channel is full, waiting for the consumer to catch up");
+                self.pending = Some(record);
+                task::current().notify();
+                Ok(Async::NotReady)
             }
-            _ => Ok(Async::NotReady),
         }
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct Record {
-    _metadata: Metadata,
-    _contents: RecordContents,
+    /// The index of the `SamplingConfig` which wrote this record, in the order the configs were
+    /// passed to the sampler. `None` if the record's PERF_SAMPLE_IDENTIFIER didn't match any of
+    /// the sampled events.
+    pub source: Option<usize>,
+    pub metadata: Metadata,
+    pub contents: RecordContents,
 }
 
 impl Record {
-    pub fn from_slice(header: EventHeader, bytes: &[u8]) -> Result<Self> {
-        use self::SampledEventType::*;
-        match header.event_type {
-            _ => unimplemented!(),
-        }
+    pub(crate) fn from_slice(
+        header: EventHeader,
+        bytes: &[u8],
+        formats: &RecordFormats,
+    ) -> Result<Self> {
+        let source = formats.source(&header, bytes);
+        let format = source.and_then(|s| formats.get(s));
+
+        let contents = match header.event_type {
+            Some(event_type) => RecordContents::parse(event_type, bytes, format)?,
+            None => {
+                debug!("unrecognized record type, keeping its bytes");
+                RecordContents::BagOBytes(bytes.to_vec())
+            }
+        };
+
+        Ok(Self {
+            source,
+            metadata: header.misc,
+            contents,
+        })
     }
 
     // TODO delete this method
     pub fn misc(contents: Vec<u8>) -> Self {
         Self {
-            _metadata: Metadata {
+            source: None,
+            metadata: Metadata {
                 _multipurpose_lol: false,
-                _cpu_mode: CpuMode::User,
-                _exact_ip: false,
+                cpu_mode: CpuMode::User,
+                exact_ip: false,
                 _reserved: false,
             },
-            _contents: RecordContents::BagOBytes(contents),
+            contents: RecordContents::BagOBytes(contents),
         }
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum RecordContents {
+    /// Records a PROT_EXEC mapping so that user-space IPs can be correlated to code.
+    Mmap {
+        pid: u32,
+        tid: u32,
+        addr: u64,
+        len: u64,
+        pgoff: u64,
+        filename: String,
+    },
+    /// Indicates that `lost` events were dropped for the event with the unique ID `id`.
+    Lost { id: u64, lost: u64 },
+    /// Indicates a change in the process name.
+    Comm { pid: u32, tid: u32, comm: String },
+    /// Indicates a process exit event.
+    Exit(TaskEvent),
+    /// Indicates that the kernel started throttling the event's interrupts.
+    Throttle(ThrottleEvent),
+    /// Indicates that the kernel stopped throttling the event's interrupts.
+    Unthrottle(ThrottleEvent),
+    /// Indicates a fork event.
+    Fork(TaskEvent),
+    /// Indicates a read event, e.g. the final counts of an inherited child task.
+    Read {
+        pid: u32,
+        tid: u32,
+        values: ReadValues,
+    },
+    /// Indicates a sample.
+    Sample(Box<Sample>),
+    /// Records a PROT_EXEC mapping with enough additional information to uniquely identify shared
+    /// mappings.
+    Mmap2 {
+        pid: u32,
+        tid: u32,
+        addr: u64,
+        len: u64,
+        pgoff: u64,
+        maj: u32,
+        min: u32,
+        ino: u64,
+        ino_generation: u64,
+        prot: u32,
+        flags: u32,
+        filename: String,
+    },
+    /// Reports that new data is available in the separate AUX buffer region. (since Linux 4.1)
+    Aux {
+        aux_offset: u64,
+        aux_size: u64,
+        flags: u64,
+    },
+    /// Indicates which process has initiated an instruction trace event. (since Linux 4.1)
+    ItraceStart { pid: u32, tid: u32 },
+    /// When using hardware sampling (such as Intel PEBS) this indicates some number of samples
+    /// that may have been lost. (since Linux 4.2)
+    LostSamples { lost: u64 },
+    /// Indicates a context switch has happened. (since Linux 4.3)
+    Switch,
+    /// Indicates a context switch has happened, with the process being switched to/from. Only
+    /// generated when sampling in CPU-wide mode. (since Linux 4.3)
+    SwitchCpuWide {
+        next_prev_pid: u32,
+        next_prev_tid: u32,
+    },
+    /// A record which this crate doesn't know how to decode (yet).
     BagOBytes(Vec<u8>),
 }
 
+impl RecordContents {
+    fn parse(
+        event_type: SampledEventType,
+        bytes: &[u8],
+        format: Option<&RecordFormat>,
+    ) -> Result<Self> {
+        use self::SampledEventType::*;
+        let mut c = Cursor::new(bytes);
+
+        Ok(match event_type {
+            Mmap => RecordContents::Mmap {
+                pid: c.read()?,
+                tid: c.read()?,
+                addr: c.read()?,
+                len: c.read()?,
+                pgoff: c.read()?,
+                filename: c.string(),
+            },
+            Lost => RecordContents::Lost {
+                id: c.read()?,
+                lost: c.read()?,
+            },
+            Comm => RecordContents::Comm {
+                pid: c.read()?,
+                tid: c.read()?,
+                comm: c.string(),
+            },
+            Exit => RecordContents::Exit(TaskEvent::parse(&mut c)?),
+            Throttle => RecordContents::Throttle(ThrottleEvent::parse(&mut c)?),
+            Unthrottle => RecordContents::Unthrottle(ThrottleEvent::parse(&mut c)?),
+            Fork => RecordContents::Fork(TaskEvent::parse(&mut c)?),
+            Read => RecordContents::Read {
+                pid: c.read()?,
+                tid: c.read()?,
+                values: ReadValues::parse(&mut c, format.map(|f| f.read_format).unwrap_or(0))?,
+            },
+            Sample => match format {
+                Some(format) => {
+                    RecordContents::Sample(Box::new(self::Sample::parse(&mut c, format)?))
+                }
+                None => {
+                    debug!("sample from an unknown event, keeping its bytes");
+                    RecordContents::BagOBytes(bytes.to_vec())
+                }
+            },
+            Mmap2 => RecordContents::Mmap2 {
+                pid: c.read()?,
+                tid: c.read()?,
+                addr: c.read()?,
+                len: c.read()?,
+                pgoff: c.read()?,
+                maj: c.read()?,
+                min: c.read()?,
+                ino: c.read()?,
+                ino_generation: c.read()?,
+                prot: c.read()?,
+                flags: c.read()?,
+                filename: c.string(),
+            },
+            Aux => RecordContents::Aux {
+                aux_offset: c.read()?,
+                aux_size: c.read()?,
+                flags: c.read()?,
+            },
+            ItraceStart => RecordContents::ItraceStart {
+                pid: c.read()?,
+                tid: c.read()?,
+            },
+            LostSamples => RecordContents::LostSamples { lost: c.read()? },
+            Switch => RecordContents::Switch,
+            SwitchCpuWide => RecordContents::SwitchCpuWide {
+                next_prev_pid: c.read()?,
+                next_prev_tid: c.read()?,
+            },
+        })
+    }
+}
+
+/// The body shared by PERF_RECORD_FORK and PERF_RECORD_EXIT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TaskEvent {
+    pub pid: u32,
+    pub ppid: u32,
+    pub tid: u32,
+    pub ptid: u32,
+    pub time: u64,
+}
+
+impl TaskEvent {
+    fn parse(c: &mut Cursor) -> Result<Self> {
+        Ok(Self {
+            pid: c.read()?,
+            ppid: c.read()?,
+            tid: c.read()?,
+            ptid: c.read()?,
+            time: c.read()?,
+        })
+    }
+}
+
+/// The body shared by PERF_RECORD_THROTTLE and PERF_RECORD_UNTHROTTLE.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ThrottleEvent {
+    pub time: u64,
+    pub id: u64,
+    pub stream_id: u64,
+}
+
+impl ThrottleEvent {
+    fn parse(c: &mut Cursor) -> Result<Self> {
+        Ok(Self {
+            time: c.read()?,
+            id: c.read()?,
+            stream_id: c.read()?,
+        })
+    }
+}
+
+/// The values of a `struct read_format`, whose layout depends on the event's read_format.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReadValues {
+    /// Present if PERF_FORMAT_TOTAL_TIME_ENABLED was requested.
+    pub time_enabled: Option<u64>,
+    /// Present if PERF_FORMAT_TOTAL_TIME_RUNNING was requested.
+    pub time_running: Option<u64>,
+    /// One value per event in the group, or a single value if PERF_FORMAT_GROUP wasn't requested.
+    pub values: Vec<ReadValue>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ReadValue {
+    pub value: u64,
+    /// Present if PERF_FORMAT_ID was requested.
+    pub id: Option<u64>,
+}
+
+impl ReadValues {
+    /// Parses what read(2) returns for an event opened with `read_format`.
+    pub(crate) fn from_bytes(bytes: &[u8], read_format: u64) -> Result<Self> {
+        Self::parse(&mut Cursor::new(bytes), read_format)
+    }
+
+    fn parse(c: &mut Cursor, read_format: u64) -> Result<Self> {
+        use raw::perf_event_read_format::*;
+        let has = |flag: perf_event_read_format::Type| read_format & flag as u64 != 0;
+
+        let mut values = ReadValues::default();
+        if has(PERF_FORMAT_GROUP) {
+            // { u64 nr; u64 time_enabled; u64 time_running; { u64 value; u64 id; } cntr[nr]; }
+            let nr: u64 = c.read()?;
+            values.time_enabled = c.read_if(has(PERF_FORMAT_TOTAL_TIME_ENABLED))?;
+            values.time_running = c.read_if(has(PERF_FORMAT_TOTAL_TIME_RUNNING))?;
+            for _ in 0..nr {
+                values.values.push(ReadValue {
+                    value: c.read()?,
+                    id: c.read_if(has(PERF_FORMAT_ID))?,
+                });
+            }
+        } else {
+            // { u64 value; u64 time_enabled; u64 time_running; u64 id; }
+            let value = c.read()?;
+            values.time_enabled = c.read_if(has(PERF_FORMAT_TOTAL_TIME_ENABLED))?;
+            values.time_running = c.read_if(has(PERF_FORMAT_TOTAL_TIME_RUNNING))?;
+            let id = c.read_if(has(PERF_FORMAT_ID))?;
+            values.values.push(ReadValue { value, id });
+        }
+
+        Ok(values)
+    }
+}
+
+/// The contents of a PERF_RECORD_SAMPLE. Which fields are present depends on the `SampleRequest`s
+/// of the event which wrote it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Sample {
+    pub identifier: Option<u64>,
+    pub ip: Option<u64>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub time: Option<u64>,
+    pub addr: Option<u64>,
+    pub id: Option<u64>,
+    pub stream_id: Option<u64>,
+    pub cpu: Option<u32>,
+    pub period: Option<u64>,
+    pub read: Option<ReadValues>,
+    pub callchain: Vec<u64>,
+    pub raw: Vec<u8>,
+    pub branch_stack: Vec<BranchEntry>,
+    pub regs_user: Option<Registers>,
+    pub stack_user: Vec<u8>,
+    pub weight: Option<u64>,
+    pub data_src: Option<u64>,
+    pub transaction: Option<u64>,
+    pub regs_intr: Option<Registers>,
+}
+
+impl Sample {
+    fn parse(c: &mut Cursor, format: &RecordFormat) -> Result<Self> {
+        use raw::perf_event_sample_format::*;
+        let has = |flag: perf_event_sample_format::Type| format.sample_type & flag as u64 != 0;
+
+        // NOTE: struct fields are evaluated in the order they're written, which is the order the
+        // kernel writes them in
+        let mut sample = Sample {
+            identifier: c.read_if(has(PERF_SAMPLE_IDENTIFIER))?,
+            ip: c.read_if(has(PERF_SAMPLE_IP))?,
+            pid: c.read_if(has(PERF_SAMPLE_TID))?,
+            tid: c.read_if(has(PERF_SAMPLE_TID))?,
+            time: c.read_if(has(PERF_SAMPLE_TIME))?,
+            addr: c.read_if(has(PERF_SAMPLE_ADDR))?,
+            id: c.read_if(has(PERF_SAMPLE_ID))?,
+            stream_id: c.read_if(has(PERF_SAMPLE_STREAM_ID))?,
+            cpu: c.read_if(has(PERF_SAMPLE_CPU))?,
+            ..Sample::default()
+        };
+        let _res: Option<u32> = c.read_if(has(PERF_SAMPLE_CPU))?;
+        sample.period = c.read_if(has(PERF_SAMPLE_PERIOD))?;
+
+        if has(PERF_SAMPLE_READ) {
+            sample.read = Some(ReadValues::parse(c, format.read_format)?);
+        }
+
+        if has(PERF_SAMPLE_CALLCHAIN) {
+            let nr: u64 = c.read()?;
+            for _ in 0..nr {
+                sample.callchain.push(c.read()?);
+            }
+        }
+
+        if has(PERF_SAMPLE_RAW) {
+            // the kernel pads size so that the u32 and the data end on a u64 boundary
+            let size: u32 = c.read()?;
+            sample.raw = c.take(size as usize)?.to_vec();
+        }
+
+        if has(PERF_SAMPLE_BRANCH_STACK) {
+            let bnr: u64 = c.read()?;
+            for _ in 0..bnr {
+                sample.branch_stack.push(BranchEntry::parse(c)?);
+            }
+        }
+
+        if has(PERF_SAMPLE_REGS_USER) {
+            sample.regs_user = Some(Registers::parse(c, format.sample_regs_user)?);
+        }
+
+        if has(PERF_SAMPLE_STACK_USER) {
+            let size: u64 = c.read()?;
+            let mut stack = c.take(size as usize)?.to_vec();
+            if size != 0 {
+                let dyn_size: u64 = c.read()?;
+                stack.truncate(dyn_size as usize);
+            }
+            sample.stack_user = stack;
+        }
+
+        sample.weight = c.read_if(has(PERF_SAMPLE_WEIGHT))?;
+        sample.data_src = c.read_if(has(PERF_SAMPLE_DATA_SRC))?;
+        sample.transaction = c.read_if(has(PERF_SAMPLE_TRANSACTION))?;
+
+        if has(PERF_SAMPLE_REGS_INTR) {
+            sample.regs_intr = Some(Registers::parse(c, format.sample_regs_intr)?);
+        }
+
+        Ok(sample)
+    }
+}
+
+/// One entry of a sampled branch stack, from most to least recent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BranchEntry {
+    /// The source instruction (may not be a branch).
+    pub from: u64,
+    /// The branch target.
+    pub to: u64,
+    /// The branch target was mispredicted.
+    pub mispredicted: bool,
+    /// The branch target was predicted.
+    pub predicted: bool,
+    /// The branch was in a transactional memory transaction. (since Linux 3.11)
+    pub in_tx: bool,
+    /// The branch was in an aborted transactional memory transaction. (since Linux 3.11)
+    pub abort: bool,
+    /// The number of cycles elapsed since the previous branch stack update. (since Linux 4.3)
+    pub cycles: u16,
+}
+
+impl BranchEntry {
+    fn parse(c: &mut Cursor) -> Result<Self> {
+        let from = c.read()?;
+        let to = c.read()?;
+        let flags: u64 = c.read()?;
+
+        Ok(Self {
+            from,
+            to,
+            mispredicted: flags & 1 != 0,
+            predicted: flags & (1 << 1) != 0,
+            in_tx: flags & (1 << 2) != 0,
+            abort: flags & (1 << 3) != 0,
+            cycles: (flags >> 4) as u16,
+        })
+    }
+}
+
+/// A register dump, with one value for each bit set in the requested register mask.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Registers {
+    /// One of PERF_SAMPLE_REGS_ABI_NONE, PERF_SAMPLE_REGS_ABI_32 or PERF_SAMPLE_REGS_ABI_64.
+    pub abi: u64,
+    pub values: Vec<u64>,
+}
+
+impl Registers {
+    fn parse(c: &mut Cursor, mask: u64) -> Result<Self> {
+        use raw::perf_sample_regs_abi::*;
+        let abi: u64 = c.read()?;
+
+        let mut values = Vec::new();
+        if abi != PERF_SAMPLE_REGS_ABI_NONE as u64 {
+            for _ in 0..mask.count_ones() {
+                values.push(c.read()?);
+            }
+        }
+
+        Ok(Self { abi, values })
+    }
+}
+
+/// The parts of an event's configuration which determine the layout of the records it writes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct RecordFormat {
+    sample_type: u64,
+    read_format: u64,
+    sample_regs_user: u64,
+    sample_regs_intr: u64,
+}
+
+impl<'a> From<&'a perf_event_attr> for RecordFormat {
+    fn from(attr: &perf_event_attr) -> Self {
+        Self {
+            sample_type: attr.sample_type,
+            read_format: attr.read_format,
+            sample_regs_user: attr.sample_regs_user,
+            sample_regs_intr: attr.sample_regs_intr,
+        }
+    }
+}
+
+/// The record layouts of every event writing to a single ring buffer, keyed by the kernel's
+/// unique ID for each event.
+#[derive(Debug, Default)]
+pub(crate) struct RecordFormats {
+    formats: Vec<RecordFormat>,
+    ids: BTreeMap<u64, usize>,
+}
+
+impl RecordFormats {
+    pub fn push(&mut self, id: u64, format: RecordFormat) {
+        self.ids.insert(id, self.formats.len());
+        self.formats.push(format);
+    }
+
+    fn get(&self, source: usize) -> Option<&RecordFormat> {
+        self.formats.get(source)
+    }
+
+    /// Finds which event wrote a record. When more than one event shares the buffer, this relies
+    /// on PERF_SAMPLE_IDENTIFIER, which the kernel places first in samples and last in every other
+    /// record when sample_id_all is set.
+    fn source(&self, header: &EventHeader, bytes: &[u8]) -> Option<usize> {
+        if self.formats.len() == 1 {
+            return Some(0);
+        }
+
+        let id_size = size_of::<u64>();
+        if bytes.len() < id_size {
+            return None;
+        }
+
+        let id_bytes = match header.event_type {
+            Some(SampledEventType::Sample) => &bytes[..id_size],
+            _ => &bytes[bytes.len() - id_size..],
+        };
+
+        let id = Cursor::new(id_bytes).read::<u64>().ok()?;
+        self.ids.get(&id).cloned()
+    }
+}
+
+/// Reads native-endian values out of a record's bytes.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let remaining = self.bytes.len() - self.offset;
+        if remaining < len {
+            Err(DecodeError::Truncated {
+                needed: len,
+                remaining,
+            })?
+        }
+
+        let taken = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(taken)
+    }
+
+    fn read<T: Copy>(&mut self) -> Result<T> {
+        let bytes = self.take(size_of::<T>())?;
+        // NOTE(unsafe) take() guarantees there are enough bytes, and records aren't guaranteed to
+        // be aligned once they've been copied out of a wrapped ring buffer
+        Ok(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+
+    fn read_if<T: Copy>(&mut self, present: bool) -> Result<Option<T>> {
+        if present {
+            self.read().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads a NUL-terminated string, consuming the rest of the record.
+    fn string(&mut self) -> String {
+        let rest = &self.bytes[self.offset..];
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        self.offset = self.bytes.len();
+        String::from_utf8_lossy(&rest[..len]).into_owned()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    /// A record was shorter than its format requires.
+    #[error("record truncated: needed {needed} more bytes but only {remaining} remained")]
+    Truncated { needed: usize, remaining: usize },
+}
+
 /// The mmap values start with a header.
 pub struct EventHeader {
-    pub(crate) event_type: SampledEventType,
+    pub(crate) event_type: Option<SampledEventType>,
     pub(crate) misc: Metadata,
     pub(crate) size: usize,
 }
@@ -90,7 +657,7 @@ impl<'a> From<&'a perf_event_header> for EventHeader {
     fn from(raw: &perf_event_header) -> Self {
         Self {
             size: raw.size as usize,
-            event_type: SampledEventType::from_u32(raw.type_).unwrap(),
+            event_type: SampledEventType::from_u32(raw.type_),
             misc: Metadata::from(raw.misc),
         }
     }
@@ -103,8 +670,9 @@ impl<'a> From<&'a perf_event_header> for EventHeader {
 ///     This bit is not set by the kernel.  It is reserved for the user-space perf
 ///     utility to indicate that /proc/i[pid]/maps parsing was taking too long and was
 ///     stopped, and thus the mmap records may be truncated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Metadata {
-    _cpu_mode: CpuMode,
+    pub cpu_mode: CpuMode,
     /// Since the following three statuses are generated by different
     /// record types, they alias to the same bit, which is represented here as
     /// a bool:
@@ -126,7 +694,7 @@ pub struct Metadata {
     _multipurpose_lol: bool,
     /// This indicates that the content of PERF_SAMPLE_IP points to the actual instruction that
     /// triggered the event.  See also perf_event_attr.precise_ip. (PERF_RECORD_MISC_EXACT_IP)
-    _exact_ip: bool,
+    pub exact_ip: bool,
     /// This indicates there is extended data available (currently not used).
     /// (PERF_RECORD_MISC_EXT_RESERVED, since Linux 2.6.35)
     _reserved: bool,
@@ -135,15 +703,16 @@ pub struct Metadata {
 impl From<u16> for Metadata {
     fn from(n: u16) -> Self {
         Self {
-            _cpu_mode: CpuMode::from(n),
-            _multipurpose_lol: (n as u32 | PERF_RECORD_MISC_MMAP_DATA) != 0,
-            _exact_ip: (n as u32 | PERF_RECORD_MISC_EXACT_IP) != 0,
-            _reserved: (n as u32 | PERF_RECORD_MISC_EXT_RESERVED) != 0,
+            cpu_mode: CpuMode::from(n),
+            _multipurpose_lol: (n as u32 & PERF_RECORD_MISC_MMAP_DATA) != 0,
+            exact_ip: (n as u32 & PERF_RECORD_MISC_EXACT_IP) != 0,
+            _reserved: (n as u32 & PERF_RECORD_MISC_EXT_RESERVED) != 0,
         }
     }
 }
 
 /// The CPU mode can be determined from this value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CpuMode {
     /// Unknown CPU mode. (PERF_RECORD_MISC_CPUMODE_UNKNOWN)
     Unknown,
@@ -161,14 +730,13 @@ pub enum CpuMode {
 
 impl From<u16> for CpuMode {
     fn from(n: u16) -> Self {
-        match n as u32 | PERF_RECORD_MISC_CPUMODE_MASK {
-            PERF_RECORD_MISC_CPUMODE_UNKNOWN => CpuMode::Unknown,
+        match n as u32 & PERF_RECORD_MISC_CPUMODE_MASK {
             PERF_RECORD_MISC_KERNEL => CpuMode::Kernel,
             PERF_RECORD_MISC_USER => CpuMode::User,
             PERF_RECORD_MISC_HYPERVISOR => CpuMode::Hypervisor,
             PERF_RECORD_MISC_GUEST_KERNEL => CpuMode::GuestKernel,
             PERF_RECORD_MISC_GUEST_USER => CpuMode::GuestUser,
-            other => panic!("unrecognized cpu mode: {}", other),
+            _ => CpuMode::Unknown,
         }
     }
 }
@@ -177,12 +745,14 @@ use raw::perf_event_type::*;
 
 enum_from_primitive! {
 #[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SampledEventType {
     Mmap = PERF_RECORD_MMAP,
     Lost = PERF_RECORD_LOST,
     Comm = PERF_RECORD_COMM,
     Exit = PERF_RECORD_EXIT,
-    ThrottleUnthrotte = PERF_RECORD_THROTTLE,
+    Throttle = PERF_RECORD_THROTTLE,
+    Unthrottle = PERF_RECORD_UNTHROTTLE,
     Fork = PERF_RECORD_FORK,
     Read = PERF_RECORD_READ,
     Sample = PERF_RECORD_SAMPLE,
@@ -737,3 +1307,106 @@ pub enum SampledEventType {
 //               next_prev_tid
 //                      The thread ID of the previous (if switching in) or
 //                      next (if switching out) thread on the CPU.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(
+        event_type: SampledEventType,
+        body: &[u64],
+        formats: &RecordFormats,
+    ) -> Result<Record> {
+        let header = EventHeader {
+            event_type: Some(event_type),
+            misc: Metadata::from(PERF_RECORD_MISC_USER as u16),
+            size: size_of::<perf_event_header>() + ::std::mem::size_of_val(body),
+        };
+        let bytes = body
+            .iter()
+            .flat_map(|word| word.to_ne_bytes().to_vec())
+            .collect::<Vec<u8>>();
+
+        Record::from_slice(header, &bytes, formats)
+    }
+
+    #[test]
+    fn demultiplexes_by_identifier() {
+        use raw::perf_event_sample_format::*;
+
+        let mut formats = RecordFormats::default();
+        formats.push(
+            11,
+            RecordFormat {
+                sample_type: (PERF_SAMPLE_IDENTIFIER | PERF_SAMPLE_IP | PERF_SAMPLE_PERIOD) as u64,
+                ..RecordFormat::default()
+            },
+        );
+        formats.push(
+            22,
+            RecordFormat {
+                sample_type: (PERF_SAMPLE_IDENTIFIER | PERF_SAMPLE_TIME) as u64,
+                ..RecordFormat::default()
+            },
+        );
+
+        let body = [22, 1234];
+        let record = decode(SampledEventType::Sample, &body, &formats).unwrap();
+        assert_eq!(record.source, Some(1));
+        assert_eq!(record.metadata.cpu_mode, CpuMode::User);
+        match record.contents {
+            RecordContents::Sample(sample) => {
+                assert_eq!(sample.identifier, Some(22));
+                assert_eq!(sample.time, Some(1234));
+                assert_eq!(sample.ip, None);
+            }
+            other => panic!("expected a sample, got {:?}", other),
+        }
+
+        let body = [11, 0xdead_beef, 4000];
+        let record = decode(SampledEventType::Sample, &body, &formats).unwrap();
+        assert_eq!(record.source, Some(0));
+        match record.contents {
+            RecordContents::Sample(sample) => {
+                assert_eq!(sample.ip, Some(0xdead_beef));
+                assert_eq!(sample.period, Some(4000));
+            }
+            other => panic!("expected a sample, got {:?}", other),
+        }
+
+        // non-sample records carry the identifier last
+        let body = [11, 7, 22];
+        let record = decode(SampledEventType::Lost, &body, &formats).unwrap();
+        assert_eq!(record.source, Some(1));
+        match record.contents {
+            RecordContents::Lost { id, lost } => {
+                assert_eq!(id, 11);
+                assert_eq!(lost, 7);
+            }
+            other => panic!("expected a lost record, got {:?}", other),
+        }
+
+        let body = [33, 0];
+        let record = decode(SampledEventType::Sample, &body, &formats).unwrap();
+        assert_eq!(record.source, None);
+    }
+
+    #[test]
+    fn truncated_sample() {
+        use raw::perf_event_sample_format::*;
+
+        let mut formats = RecordFormats::default();
+        formats.push(
+            1,
+            RecordFormat {
+                sample_type: (PERF_SAMPLE_IP | PERF_SAMPLE_CALLCHAIN) as u64,
+                ..RecordFormat::default()
+            },
+        );
+
+        // the callchain claims three entries but only has one
+        let body = [0x1000, 3, 0x2000];
+        let res = decode(SampledEventType::Sample, &body, &formats);
+        assert!(res.is_err());
+    }
+}
@@ -1,8 +1,9 @@
 use std::{
     borrow::Cow,
+    io::Read,
     mem::size_of,
-    os::unix::io::AsRawFd,
-    ptr::NonNull,
+    os::unix::io::{AsRawFd, RawFd},
+    ptr::{self, NonNull},
     sync::atomic::{fence, Ordering},
 };
 
@@ -15,25 +16,53 @@ use page_size::get as page_size;
 use tokio::reactor::PollEvented2;
 
 use super::{
-    config::SamplingConfig,
-    record::{EventHeader, Record},
+    config::{SampleRequest, SamplingConfig},
+    record::{EventHeader, Record, RecordFormat, RecordFormats},
 };
 use error::*;
-use fd::PerfFile;
+use fallback::Opened;
+use fd::{AttrExtension, PerfFile};
 use raw::*;
+use self_monitor::PerfClock;
 
 /// When using perf_event_open() in sampled mode, asynchronous events (like counter overflow or
 /// PROT_EXEC mmap tracking) are logged into a ring-buffer. This ring-buffer is created and accessed
 /// through mmap(2).
+///
+/// Several events can share one ring buffer, in which case every event after the first has its
+/// output redirected with PERF_EVENT_IOC_SET_OUTPUT and records are attributed to the event which
+/// wrote them using PERF_SAMPLE_IDENTIFIER.
 pub(crate) struct RingBuffer {
+    mode: BufferMode,
+    /// The start of the mapping, which is also where the metadata page is.
     metadata: NonNull<MmapHeader>,
+    /// The length of the whole mapping, metadata page included.
+    len: usize,
     poller: PollEvented2<PerfFile>,
+    redirected: Vec<PerfFile>,
+    /// What was opened for each config, in order.
+    opened: Vec<Opened>,
+    formats: RecordFormats,
     data_section_start: NonNull<u8>,
-    prev: usize,
-    start: usize,
-    end: usize,
+    /// How far we've read into the data section, in the same (unwrapped) units as data_head.
+    position: usize,
 }
 
+/// Whether the kernel waits for records to be consumed or overwrites the oldest ones.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BufferMode {
+    /// The buffer is mapped PROT_WRITE and records are consumed as they arrive by advancing
+    /// data_tail. The kernel drops new records when the buffer is full.
+    Consume,
+    /// The buffer is mapped read-only and the events use write_backward, so the kernel keeps
+    /// overwriting the oldest records. Output must be paused before reading. (since Linux 4.7)
+    Overwrite,
+}
+
+// NOTE(unsafe) the mapping is owned by the buffer and only accessed through it, so it can be
+// handed off to the sampler thread
+unsafe impl Send for RingBuffer {}
+
 #[repr(C)]
 struct MmapHeader {
     inner: perf_event_mmap_page,
@@ -65,15 +94,13 @@ impl MmapHeader {
     /// When the mapping is PROT_WRITE, the data_tail value should be written by user space to
     /// reflect the last read data.  In this case, the kernel will not overwrite unread data.
     fn set_tail_index(&mut self, new_tail: usize) {
-        // NOTE(anp): we guarantee PROT_WRITE in our constructors
+        // NOTE(anp): only BufferMode::Consume buffers are mapped PROT_WRITE, callers must check
         fence(Ordering::Release); // i *think* this corresponds to mb() (mfence on x86)
         self.inner.data_tail = new_tail as u64;
     }
 }
 
 impl RingBuffer {
-    const DEFAULT_PAGES: usize = 128;
-
     fn header(&self) -> &MmapHeader {
         unsafe { self.metadata.as_ref() }
     }
@@ -82,41 +109,117 @@ impl RingBuffer {
         unsafe { self.metadata.as_mut() }
     }
 
-    /// Creates a new buffer, 8k pages by default.
-    ///
-    /// TODO(anp): validate this default size in literally any way.
-    pub fn new(sample_config: SamplingConfig) -> Result<Self> {
-        Self::with_page_capacity(sample_config, Self::DEFAULT_PAGES)
+    /// Creates a new buffer which all of the provided events write to, sized according to the
+    /// first event's `buffer_size`.
+    pub fn new(sample_configs: Vec<SamplingConfig>) -> Result<Self> {
+        Self::with_mode(sample_configs, BufferMode::Consume)
+    }
+
+    /// Creates a new read-only buffer which the kernel continuously overwrites, sized according
+    /// to the first event's `buffer_size`. Records can only be read with `snapshot`.
+    pub fn overwritable(sample_configs: Vec<SamplingConfig>) -> Result<Self> {
+        Self::with_mode(sample_configs, BufferMode::Overwrite)
     }
 
     pub fn enable_fd(&self) -> Result<()> {
-        self.poller.get_ref().enable()
+        self.poller.get_ref().enable()?;
+        for file in &self.redirected {
+            file.enable()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the current count of the buffer's first event, which is still a counter even while
+    /// it's writing records.
+    pub(crate) fn read_count(&self) -> Result<u64> {
+        let mut count = [0u8; 8];
+        (&self.poller.get_ref().0).read_exact(&mut count)?;
+        Ok(u64::from_ne_bytes(count))
     }
 
-    fn with_page_capacity(sample_config: SamplingConfig, pages: usize) -> Result<Self> {
+    fn with_mode(mut sample_configs: Vec<SamplingConfig>, mode: BufferMode) -> Result<Self> {
+        if sample_configs.is_empty() {
+            return Err(Error::Misc {
+                inner: "a ring buffer needs at least one event to sample".into(),
+            });
+        }
+
+        // one page of metadata precedes the data section
+        let pages = sample_configs[0].buffer_size.data_pages()?;
         let len = (pages + 1) * page_size();
-        // FIXME(anp): this should return an Err
-        assert!(pages != 0 && (pages & (pages - 1)) == 0);
-        // make sure we're aligned on a page boundary for the length we request
-        assert!(len % page_size() == 0);
 
-        let file = PerfFile::new(sample_config)?;
+        if sample_configs.len() > 1 {
+            // the identifier is the only way to tell which event wrote a record
+            for config in &mut sample_configs {
+                config.sample_id_all = true;
+                if !config.requests.contains(&SampleRequest::Identifier) {
+                    config.requests.push(SampleRequest::Identifier);
+                }
+            }
+        }
 
-        let fd = file.0.as_raw_fd();
+        let mut formats = RecordFormats::default();
+        let mut files = Vec::new();
+        let mut opened_events = Vec::new();
+        for config in sample_configs {
+            let (pid, cpu, flags) = (
+                config.shared.pid.clone(),
+                config.shared.cpu,
+                config.shared.flags,
+            );
+            let signal = config.signal;
+            let requested = Opened::new(
+                config.event,
+                config.precise_ip,
+                config.shared.exclude_kernel,
+                config.shared.exclude_hv,
+            );
+            let fallbacks = config.fallbacks.clone();
+
+            let ((file, attr), opened) = requested.with_fallbacks(&fallbacks, |attempt| {
+                let mut config = config.clone();
+                config.event = attempt.event;
+                config.precise_ip = attempt.precise_ip;
+                config.shared.exclude_kernel = attempt.exclude_kernel;
+                config.shared.exclude_hv = attempt.exclude_hv;
+
+                let mut attr: perf_event_attr = config.into();
+                attr.set_write_backward((mode == BufferMode::Overwrite) as u64);
+                Ok((PerfFile::open(&attr, &pid, cpu, flags)?, attr))
+            })?;
+            opened_events.push(opened);
+
+            if let Some(target) = signal {
+                target.apply(&file)?;
+            }
+            formats.push(file.id()?, RecordFormat::from(&attr));
+            files.push(file);
+        }
 
-        let base = unsafe {
-            libc::mmap(
-                ::std::ptr::null_mut(),
-                len,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                fd,
-                0,
-            )
+        let prot = match mode {
+            BufferMode::Consume => libc::PROT_READ | libc::PROT_WRITE,
+            BufferMode::Overwrite => libc::PROT_READ,
         };
 
+        let file = files.remove(0);
+        let fd = file.0.as_raw_fd();
+
+        let base =
+            unsafe { libc::mmap(::std::ptr::null_mut(), len, prot, libc::MAP_SHARED, fd, 0) };
+
         if base == libc::MAP_FAILED {
-            Err(BufferError::from_i32(errno()).unwrap())?
+            let errno = errno();
+            if errno == libc::EPERM {
+                // PROT_EXEC isn't requested and perf files can't be sealed, so the only way to get
+                // here is by running out of locked memory
+                if let Some(limit_kb) = mlock_limit_kb() {
+                    Err(BufferSizeError::ExceedsMlockLimit {
+                        requested_kb: len / 1024,
+                        limit_kb,
+                    })?
+                }
+            }
+            Err(BufferError::from_i32(errno).unwrap())?
         }
 
         let metadata = NonNull::new(base as *const _ as *mut perf_event_mmap_page)
@@ -127,14 +230,26 @@ impl RingBuffer {
             base.offset(metadata.as_ref().data_section_start_index() as isize) as *mut u8
         }).unwrap();
 
-        Ok(Self {
+        // built before redirecting so that the mapping is unmapped if redirecting fails
+        let mut ring = Self {
+            mode,
             poller: PollEvented2::new(file),
+            redirected: Vec::new(),
+            opened: opened_events,
+            formats,
             data_section_start,
             metadata,
-            prev: 0,
-            end: 0,
-            start: 0,
-        })
+            len,
+            position: 0,
+        };
+
+        // the kernel only redirects output into a buffer which has already been mapped
+        for redirected in &files {
+            redirected.set_output(ring.poller.get_ref())?;
+        }
+        ring.redirected = files;
+
+        Ok(ring)
     }
 
     fn data(&self) -> &[u8] {
@@ -146,9 +261,114 @@ impl RingBuffer {
         }
     }
 
+    /// Returns `len` bytes starting at `position`, copying them if they wrap around the end of
+    /// the data section.
+    fn data_at(&self, position: usize, len: usize) -> Cow<[u8]> {
+        let data = self.data();
+        let start = position % data.len();
+
+        if start + len <= data.len() {
+            Cow::Borrowed(&data[start..start + len])
+        } else {
+            let mut wrapped = data[start..].to_vec();
+            wrapped.extend_from_slice(&data[..len - wrapped.len()]);
+            Cow::Owned(wrapped)
+        }
+    }
+
+    /// Decodes every record still held in an overwritable buffer, oldest first.
+    ///
+    /// The kernel writes backward from data_head, so the newest record starts at data_head and
+    /// older ones follow it until either the data section has been covered or we reach space
+    /// which hasn't been written yet.
+    pub fn snapshot(&self) -> Result<Vec<Record>> {
+        assert_eq!(self.mode, BufferMode::Overwrite);
+
+        let header_size = size_of::<perf_event_header>();
+        let len = self.header().data_section_len();
+
+        self.poller.get_ref().pause_output(true)?;
+        let head = self.header().head_index();
+
+        let mut records = Vec::new();
+        let mut position = head;
+        let res = loop {
+            let read = position.wrapping_sub(head);
+            if read + header_size > len {
+                break Ok(());
+            }
+
+            // NOTE(unsafe) data_at returns at least header_size bytes, which might be unaligned
+            let raw_header: perf_event_header = unsafe {
+                ptr::read_unaligned(
+                    self.data_at(position, header_size).as_ptr() as *const perf_event_header
+                )
+            };
+            let header = EventHeader::from(&raw_header);
+
+            // zeroed space hasn't been written yet, and a record which runs past the end of the
+            // data section has had its start overwritten by the newest records
+            if header.size < header_size || read + header.size > len {
+                break Ok(());
+            }
+
+            let body = self.data_at(position.wrapping_add(header_size), header.size - header_size);
+            position = position.wrapping_add(header.size);
+
+            match Record::from_slice(header, &body, &self.formats) {
+                Ok(record) => records.push(record),
+                Err(why) => break Err(why),
+            }
+        };
+
+        self.poller.get_ref().pause_output(false)?;
+        res?;
+
+        records.reverse();
+        Ok(records)
+    }
+
+    /// Returns the parameters for converting between the hardware clock and the timestamps in
+    /// this buffer's records, if the hardware has a constant and nonstop clock.
+    pub fn clock(&self) -> Option<PerfClock> {
+        // NOTE(unsafe) MmapHeader is repr(C) around the page, which is mapped while we are
+        unsafe { PerfClock::from_page(self.metadata.as_ptr() as *const perf_event_mmap_page) }
+    }
+
+    /// What was opened for each of the buffer's configs, after any fallbacks.
+    pub fn opened(&self) -> &[Opened] {
+        &self.opened
+    }
+
+    /// Fields of the events' attrs which the kernel didn't support, and which were dropped to
+    /// open them.
+    pub fn dropped(&self) -> Vec<AttrExtension> {
+        let mut dropped = Some(self.poller.get_ref())
+            .into_iter()
+            .chain(&self.redirected)
+            .flat_map(|file| file.dropped().iter().cloned())
+            .collect::<Vec<_>>();
+        dropped.sort();
+        dropped.dedup();
+        dropped
+    }
+
+    /// The number of bytes which have been read out of the buffer since it was created.
+    pub fn bytes_read(&self) -> usize {
+        self.position
+    }
+
     pub fn is_empty(&self) -> bool {
         // 	TODO handle aux map;
-        self.header().head_index() == self.prev
+        self.header().head_index() == self.position
+    }
+}
+
+impl AsRawFd for RingBuffer {
+    /// The file descriptor which the buffer is mapped from, which polls readable when the kernel
+    /// has written records according to the events' `WakeupConfig`.
+    fn as_raw_fd(&self) -> RawFd {
+        self.poller.get_ref().0.as_raw_fd()
     }
 }
 
@@ -171,21 +391,18 @@ impl Stream for RingBuffer {
         // }
 
         trace!("ring buffer polled");
-        let res = if let Async::Ready(_) = self.poller.poll_read_ready(Ready::readable())? {
-            info!("file descriptor was ready, parsing records");
-            self.next()
-        } else {
-            None
-        };
-
-        trace!("clearing fd readiness");
-        self.poller.clear_read_ready(Ready::readable())?;
+        if let Async::NotReady = self.poller.poll_read_ready(Ready::readable())? {
+            return Ok(Async::NotReady);
+        }
 
-        if let Some(r) = res {
-            Ok(Async::Ready(Some(r?)))
-        } else {
-            Ok(Async::NotReady)
+        info!("file descriptor was ready, parsing records");
+        if let Some(r) = self.next() {
+            return Ok(Async::Ready(Some(r?)));
         }
+
+        trace!("buffer drained, clearing fd readiness");
+        self.poller.clear_read_ready(Ready::readable())?;
+        Ok(Async::NotReady)
     }
 }
 
@@ -194,203 +411,171 @@ impl Iterator for RingBuffer {
 
     fn next(&mut self) -> Option<Self::Item> {
         trace!("next record...");
-        let (header, event_bytes) = self.next_event_bytes()?;
+        let (header, body) = self.next_event()?;
+        let body_len = header.size - size_of::<perf_event_header>();
+        let event_bytes = self.data_at(body, body_len);
         info!("parsing record");
-        Some(Record::from_slice(header, &event_bytes))
+        Some(Record::from_slice(header, &event_bytes, &self.formats))
     }
 }
 
 impl RingBuffer {
-    fn next_event_bytes(&mut self) -> Option<(EventHeader, Cow<[u8]>)> {
+    /// Advances past the next complete record in the buffer, returning its header and the
+    /// position of the bytes which follow the header.
+    fn next_event(&mut self) -> Option<(EventHeader, usize)> {
         let header_size = size_of::<perf_event_header>();
-        unsafe {
-            self.end = self.header().head_index();
 
-            assert!(
-                self.end >= self.start,
-                "we wrapped around and we dont support that yet lol"
-            );
-
-            let diff = self.end - self.start;
+        debug_assert_eq!(self.mode, BufferMode::Consume);
 
-            if diff < header_size {
-                debug!("gap between start and end is too small for a header");
-                return None;
-            }
+        // everything before our position has been decoded, so the kernel can reuse that space
+        let consumed = self.position;
+        self.header_mut().set_tail_index(consumed);
 
-            let data = self.data_section_start;
+        let diff = self.header().head_index() - self.position;
 
-            let raw_header: &perf_event_header =
-                &*(data.as_ptr().offset(self.start as isize) as *const perf_event_header);
-            let header = ::sample::record::EventHeader::from(raw_header);
-            let event_size = header.size;
+        if diff < header_size {
+            debug!("gap between start and end is too small for a header");
+            return None;
+        }
 
-            if event_size < header_size {
-                debug!("reported event size is too small, no data here");
-                return None;
-            }
+        // NOTE(unsafe) data_at returns at least header_size bytes, which might be unaligned
+        let raw_header: perf_event_header = unsafe {
+            ptr::read_unaligned(
+                self.data_at(self.position, header_size).as_ptr() as *const perf_event_header
+            )
+        };
+        let header = EventHeader::from(&raw_header);
 
-            if diff < event_size {
-                debug!("gap between start and and is too small for described event");
-                return None;
-            }
+        if header.size < header_size {
+            debug!("reported event size is too small, no data here");
+            return None;
+        }
 
-            let event_start =
-                (raw_header as *const _ as *const libc::c_void).offset(header_size as isize);
+        if diff < header.size {
+            debug!("gap between start and and is too small for described event");
+            return None;
+        }
 
-            let start = self.start;
-            self.header_mut().set_tail_index(start);
-            self.prev = self.header().head_index();
+        let body = self.position + header_size;
+        self.position += header.size;
+        Some((header, body))
+    }
+}
 
-            None
+impl ::std::ops::Drop for RingBuffer {
+    fn drop(&mut self) {
+        // the files are closed after this when the fields are dropped, which is fine because the
+        // kernel keeps the buffer alive until both the mapping and the leader's fd are released
+        let res = unsafe { libc::munmap(self.metadata.as_ptr() as *mut libc::c_void, self.len) };
+        if res != 0 {
+            warn!("unable to unmap a ring buffer: {:?}", BufferError::from_i32(errno()));
         }
     }
+}
 
-    // Time the event was active.
-    //
-    // Note: author of this crate isn't *entirely* sure of the semantics here either.
-    // fn time_enabled(&self) -> u64 {
-    //     self.metadata.time_enabled
-    // }
-
-    // Time the event was running.
-    //
-    // Note: author of this crate isn't *entirely* sure of the semantics here either.
-    // fn time_running(&self) -> u64 {
-    //     self.metadata.time_running
-    // }
-
-    // cap_user_time (since Linux 3.12)
-    //        This bit indicates the hardware has a constant, nonstop time‐
-    //        stamp counter (TSC on x86).
-
-    // cap_user_time_zero (since Linux 3.12)
-    //        Indicates the presence of time_zero which allows mapping time‐
-    //        stamp values to the hardware clock.
-
-    // time_shift, time_mult, time_offset
-
-    //        If cap_usr_time, these fields can be used to compute the time
-    //        delta since time_enabled (in nanoseconds) using rdtsc or simi‐
-    //        lar.
-
-    //            u64 quot, rem;
-    //            u64 delta;
-    //            quot = (cyc >> time_shift);
-    //            rem = cyc & (((u64)1 << time_shift) - 1);
-    //            delta = time_offset + quot * time_mult +
-    //                    ((rem * time_mult) >> time_shift);
-
-    //        Where time_offset, time_mult, time_shift, and cyc are read in
-    //        the seqcount loop described above.  This delta can then be
-    //        added to enabled and possible running (if idx), improving the
-    //        scaling:
-
-    //            enabled += delta;
-    //            if (idx)
-    //                running += delta;
-    //            quot = count / running;
-    //            rem  = count % running;
-    //            count = quot * enabled + (rem * enabled) / running;
-
-    // time_zero (since Linux 3.12)
-
-    //        If cap_usr_time_zero is set, then the hardware clock (the TSC
-    //        timestamp counter on x86) can be calculated from the
-    //        time_zero, time_mult, and time_shift values:
-
-    //            time = timestamp - time_zero;
-    //            quot = time / time_mult;
-    //            rem  = time % time_mult;
-    //            cyc = (quot << time_shift) + (rem << time_shift) / time_mult;
-
-    //        And vice versa:
-
-    //            quot = cyc >> time_shift;
-    //            rem  = cyc & (((u64)1 << time_shift) - 1);
-    //            timestamp = time_zero + quot * time_mult +
-    //                ((rem * time_mult) >> time_shift);
+/// Reads the number of KiB of locked memory each unprivileged user may use for perf_events ring
+/// buffers on each CPU, beyond their RLIMIT_MEMLOCK.
+fn mlock_limit_kb() -> Option<usize> {
+    ::std::fs::read_to_string("/proc/sys/kernel/perf_event_mlock_kb")
+        .ok()
+        .and_then(|limit| limit.trim().parse().ok())
 }
 
-// impl ::std::ops::Drop for RingBuffer {
-//     fn drop(&mut self) {
-//         unsafe {
-//             libc::munmap(self.base, self.len);
-//         }
-//     }
-// }
+/// Reasons a ring buffer of the requested size can't be mapped.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum BufferSizeError {
+    /// A ring buffer needs at least one page of data.
+    #[error("a ring buffer needs at least one page of data")]
+    Empty,
+
+    /// The data section of a ring buffer must be a power of two number of pages.
+    #[error("{pages} pages isn't a power of two")]
+    NotPowerOfTwo { pages: usize },
+
+    /// The size in bytes overflows, or can't be mapped on this platform.
+    #[error("{bytes} bytes is too large to map")]
+    TooLarge { bytes: usize },
+
+    /// Mapping the buffer exceeds the locked memory allowed for perf_events: limit_kb per CPU (see
+    /// /proc/sys/kernel/perf_event_mlock_kb) plus RLIMIT_MEMLOCK. Request a smaller buffer, raise
+    /// the limit, or run with CAP_IPC_LOCK.
+    #[error("{requested_kb}KiB exceeds the perf_events mlock limit of {limit_kb}KiB")]
+    ExceedsMlockLimit {
+        requested_kb: usize,
+        limit_kb: usize,
+    },
+}
 
 enum_from_primitive! {
 #[repr(i32)]
-#[derive(Debug, Fail)]
+#[derive(Debug, Error)]
 pub enum BufferError {
-    #[fail(
-        display = "A file descriptor refers to a non-regular file.  Or a file mapping was requested,
-        but fd is not open for reading.  Or MAP_SHARED was requested and PROT_WRITE is set, but fd
-        is not open in read/write (O_RDWR) mode.  Or PROT_WRITE is set, but the file is append-only."
-    )]
+    /// A file descriptor refers to a non-regular file. Or a file mapping was requested, but fd is
+    /// not open for reading. Or MAP_SHARED was requested and PROT_WRITE is set, but fd is not open
+    /// in read/write (O_RDWR) mode. Or PROT_WRITE is set, but the file is append-only.
+    #[error("EACCES: access denied")]
     Access = libc::EACCES,
 
-    #[fail(display = "fd is not a valid file descriptor (and MAP_ANONYMOUS was not set).")]
+    /// fd is not a valid file descriptor (and MAP_ANONYMOUS was not set).
+    #[error("EBADF: bad file descriptor")]
     FdBad = libc::EBADF,
 
-    #[fail(
-        display = "We don't like addr, length, or offset (e.g., they are too large, or not aligned
-        on a page boundary). length was 0. flags contained neither MAP_PRIVATE or MAP_SHARED, or
-        contained both of these values."
-    )]
+    /// We don't like addr, length, or offset (e.g., they are too large, or not aligned on a page
+    /// boundary). length was 0. flags contained neither MAP_PRIVATE or MAP_SHARED, or contained
+    /// both of these values.
+    #[error("EINVAL: invalid arguments")]
     InvalidArgs = libc::EINVAL,
 
-    #[fail(
-        display = "The underlying filesystem of the specified file does not support memory mapping."
-    )]
+    /// The underlying filesystem of the specified file does not support memory mapping.
+    #[error("ENODEV: mapping isn't supported")]
     NoMapSupport = libc::ENODEV,
 
-    #[fail(
-        display = "No memory is available. -or-
-
-        The process's maximum number of mappings would have been exceeded. This error can also
-        occur for munmap(), when unmapping a region in the middle of an existing mapping, since this
-        results in two smaller mappings on either side of the region being unmapped. -or-
-
-        (since Linux 4.7) The process's RLIMIT_DATA limit, described in getrlimit(2), would have
-        been exceeded."
-    )]
+    /// No memory is available.
+    ///
+    /// -or-
+    ///
+    /// The process's maximum number of mappings would have been exceeded. This error can also occur
+    /// for munmap(), when unmapping a region in the middle of an existing mapping, since this
+    /// results in two smaller mappings on either side of the region being unmapped.
+    ///
+    /// -or-
+    ///
+    /// (since Linux 4.7) The process's RLIMIT_DATA limit, described in getrlimit(2), would have
+    /// been exceeded.
+    #[error("ENOMEM: out of memory or mappings")]
     NoMemory = libc::ENOMEM,
 
-    #[fail(
-        display = "The file has been locked, or too much memory has been locked (see setrlimit(2))."
-    )]
+    /// The file has been locked, or too much memory has been locked (see setrlimit(2)).
+    #[error("EAGAIN: too much memory is locked")]
     TooMuchLocking = libc::EAGAIN,
 
 
-    #[fail(
-        display = "MAP_FIXED_NOREPLACE was specified in flags, and the range covered by addr and
-        length is clashes with an existing mapping."
-    )]
+    /// MAP_FIXED_NOREPLACE was specified in flags, and the range covered by addr and length is
+    /// clashes with an existing mapping.
+    #[error("EEXIST: clashes with an existing mapping")]
     ClashesWithExisting = libc::EEXIST,
 
-    #[fail(display = "The system-wide limit on the total number of open files has been reached.")]
+    /// The system-wide limit on the total number of open files has been reached.
+    #[error("ENFILE: too many open files in the system")]
     TooManyOpenFiles = libc::ENFILE,
 
-    #[fail(
-        display = "On 32-bit architecture together with the large file extension (i.e., using 64-bit
-        off_t): the number of pages used for length plus number of pages used for offset would
-        overflow unsigned long (32 bits)."
-    )]
+    /// On 32-bit architecture together with the large file extension (i.e., using 64-bit off_t):
+    /// the number of pages used for length plus number of pages used for offset would overflow
+    /// unsigned long (32 bits).
+    #[error("EOVERFLOW: the mapping is too large")]
     Overflow = libc::EOVERFLOW,
 
-    #[fail(
-        display = "The prot argument asks for PROT_EXEC but the mapped area belongs to a file on a
-        filesystem that was mounted no-exec. -or-
-
-        The operation was prevented by a file seal; see fcntl(2)."
-    )]
+    /// The prot argument asks for PROT_EXEC but the mapped area belongs to a file on a filesystem
+    /// that was mounted no-exec.
+    ///
+    /// -or-
+    ///
+    /// The operation was prevented by a file seal; see fcntl(2).
+    #[error("EPERM: operation not permitted")]
     ExecFailed = libc::EPERM,
 
-    #[fail(
-        display = "MAP_DENYWRITE was set but the object specified by fd is open for writing."
-    )]
+    /// MAP_DENYWRITE was set but the object specified by fd is open for writing.
+    #[error("ETXTBSY: the file is open for writing")]
     DenyWriteFailed = libc::ETXTBSY,
 }
 }
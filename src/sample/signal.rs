@@ -0,0 +1,270 @@
+use std::{
+    mem::{transmute, zeroed},
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use libc::{self, c_int, c_long, c_void, pid_t, siginfo_t};
+use nix::errno::Errno;
+
+use super::config::SamplingConfig;
+use error::*;
+use fallback::Opened;
+use fd::PerfFile;
+
+/// A thread to deliver a signal to whenever an event notifies its readers. This replaces the
+/// default SIGIO so that overflows can be told apart from other IO, and targets a single thread
+/// rather than the whole process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SignalTarget {
+    pub signal: c_int,
+    /// The kernel thread ID (as returned by gettid(2)) which receives the signal.
+    pub thread: pid_t,
+}
+
+impl SignalTarget {
+    /// Delivers `signal` to the calling thread. Real-time signals (e.g. `libc::SIGRTMIN() + 1`)
+    /// are queued rather than merged, so each notification is delivered.
+    pub fn current_thread(signal: c_int) -> Self {
+        // NOTE(unsafe) gettid can't fail
+        let thread = unsafe { libc::syscall(libc::SYS_gettid) } as pid_t;
+        Self { signal, thread }
+    }
+
+    pub(crate) fn apply(&self, file: &PerfFile) -> Result<()> {
+        file.signal_thread(self.thread, self.signal)
+    }
+}
+
+/// A sampled event which isn't read through a ring buffer, only used to deliver a signal to a
+/// thread after a number of overflows, e.g. to assert on what a thread is doing every N
+/// instructions.
+///
+/// The event starts disabled, and is enabled by `refresh`.
+pub struct OverflowSignal {
+    file: PerfFile,
+    opened: Opened,
+}
+
+impl OverflowSignal {
+    /// Opens the config's event, or the first of its fallbacks which can be, to deliver signals
+    /// to its `signal` target, which must be set.
+    pub fn new(config: SamplingConfig) -> Result<Self> {
+        let target = config.signal.ok_or_else(|| Error::Misc {
+            inner: "an OverflowSignal needs a SamplingConfig::signal to deliver to".into(),
+        })?;
+        let (pid, cpu, flags) = (
+            config.shared.pid.clone(),
+            config.shared.cpu,
+            config.shared.flags,
+        );
+        let requested = Opened::new(
+            config.event,
+            config.precise_ip,
+            config.shared.exclude_kernel,
+            config.shared.exclude_hv,
+        );
+
+        let (file, opened) = requested.with_fallbacks(&config.fallbacks, |attempt| {
+            let mut config = config.clone();
+            config.event = attempt.event;
+            config.precise_ip = attempt.precise_ip;
+            config.shared.exclude_kernel = attempt.exclude_kernel;
+            config.shared.exclude_hv = attempt.exclude_hv;
+            PerfFile::open(&config.into(), &pid, cpu, flags)
+        })?;
+        target.apply(&file)?;
+        Ok(Self { file, opened })
+    }
+
+    /// Returns what was opened, after any of the config's fallbacks.
+    pub fn opened(&self) -> &Opened {
+        &self.opened
+    }
+
+    /// Enables the event until it has overflowed `overflows` more times, after which it's
+    /// disabled. The signal is delivered on every overflow, with `OverflowCode::Limit` for the
+    /// last one. Each overflow happens after the config's sampling period has elapsed.
+    pub fn refresh(&self, overflows: u32) -> Result<()> {
+        if overflows == 0 {
+            return Err(Error::Misc {
+                inner: "refreshing an event for 0 overflows is undefined".into(),
+            });
+        }
+        self.file.refresh(overflows as c_int)
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        self.file.enable()
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        self.file.disable()
+    }
+}
+
+impl AsRawFd for OverflowSignal {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A notification delivered by an event, as passed to a handler registered with
+/// `set_overflow_handler`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Overflow {
+    /// The file descriptor of the event which sent the signal, to compare with the `AsRawFd`
+    /// implementations of `OverflowSignal` or the samplers.
+    pub fd: RawFd,
+    pub code: OverflowCode,
+}
+
+/// Why an event sent a signal, from the siginfo_t's si_code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowCode {
+    /// POLL_IN: the event overflowed, or its ring buffer has records to read according to the
+    /// event's `WakeupConfig`.
+    Wakeup,
+    /// POLL_HUP: the event reached the overflow limit set by `OverflowSignal::refresh` and has
+    /// been disabled.
+    Limit,
+    Other(c_int),
+}
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/siginfo.h
+// #define POLL_IN	(__SI_POLL|1)	/* data input available */
+const POLL_IN: c_int = 1;
+// #define POLL_HUP	(__SI_POLL|6)	/* device disconnected */
+const POLL_HUP: c_int = 6;
+
+// the sigpoll member of siginfo_t's union, which is all that's sent for F_SETSIG
+#[repr(C)]
+struct SigPollInfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    si_band: c_long,
+    si_fd: c_int,
+}
+
+// indexed by signal number, null if no handler is set
+static HANDLERS: [AtomicPtr<()>; 65] = [const { AtomicPtr::new(ptr::null_mut()) }; 65];
+
+/// Calls `handler` on whichever thread receives `signal`, every time it's delivered. `signal`
+/// must be SIGIO or a real-time signal, so that signals which the runtime or the rest of the
+/// process relies on (e.g. SIGSEGV) can't be replaced.
+///
+/// Replaces any handler previously set for the signal by this function or by sigaction(2).
+///
+/// # Safety
+///
+/// The handler runs in signal context, interrupting whatever the receiving thread was doing, so
+/// it must only do async-signal-safe work, e.g. updating atomics or writing to a pipe. It mustn't
+/// allocate, print, panic or take locks which the interrupted code might hold.
+pub unsafe fn set_overflow_handler(signal: c_int, handler: fn(Overflow)) -> Result<()> {
+    let realtime = signal >= libc::SIGRTMIN() && signal <= libc::SIGRTMAX();
+    if !(signal == libc::SIGIO || realtime) || signal as usize >= HANDLERS.len() {
+        return Err(Error::Posix {
+            call: "sigaction",
+            inner: ::nix::Error::Sys(Errno::EINVAL),
+        });
+    }
+
+    HANDLERS[signal as usize].store(handler as *mut (), Ordering::SeqCst);
+
+    // NOTE(unsafe) dispatch only reads from the siginfo_t and the handler table before calling
+    // the handler, which the caller promises is async-signal-safe
+    let mut action: libc::sigaction = zeroed();
+    action.sa_sigaction = dispatch as extern "C" fn(_, _, _) as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    if libc::sigaction(signal, &action, ptr::null_mut()) == -1 {
+        return Err(Error::Posix {
+            call: "sigaction",
+            inner: ::nix::Error::Sys(Errno::last()),
+        });
+    }
+
+    Ok(())
+}
+
+extern "C" fn dispatch(signal: c_int, info: *mut siginfo_t, _context: *mut c_void) {
+    let handler = HANDLERS[signal as usize].load(Ordering::SeqCst);
+    if handler.is_null() || info.is_null() {
+        return;
+    }
+
+    // NOTE(unsafe) the kernel fills in the sigpoll fields for signals sent by F_SETSIG, and the
+    // only pointers stored in the table are fn(Overflow)
+    unsafe {
+        let info = &*(info as *const SigPollInfo);
+        let handler: fn(Overflow) = transmute(handler);
+
+        handler(Overflow {
+            fd: info.si_fd,
+            code: match info.si_code {
+                POLL_IN => OverflowCode::Wakeup,
+                POLL_HUP => OverflowCode::Limit,
+                other => OverflowCode::Other(other),
+            },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sample::config::SamplingRate;
+    use std::sync::atomic::AtomicUsize;
+    use {Counted, SwEvent};
+
+    static WAKEUPS: AtomicUsize = AtomicUsize::new(0);
+    static LIMITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(overflow: Overflow) {
+        match overflow.code {
+            OverflowCode::Wakeup => WAKEUPS.fetch_add(1, Ordering::SeqCst),
+            OverflowCode::Limit => LIMITS.fetch_add(1, Ordering::SeqCst),
+            OverflowCode::Other(_) => 0,
+        };
+    }
+
+    #[test]
+    fn refreshed_overflows() {
+        let signal = libc::SIGRTMIN() + 1;
+        // NOTE(unsafe) count only updates atomics
+        unsafe { set_overflow_handler(signal, count).unwrap() };
+        for rejected in &[libc::SIGSEGV, libc::SIGBUS, 0, 65] {
+            assert!(unsafe { set_overflow_handler(*rejected, count) }.is_err());
+        }
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(1_000_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+        assert!(OverflowSignal::new(config.clone()).is_err());
+
+        let config = SamplingConfig {
+            signal: Some(SignalTarget::current_thread(signal)),
+            ..config
+        };
+        let overflows = OverflowSignal::new(config).unwrap();
+        assert!(!overflows.opened().fell_back());
+        overflows.refresh(3).unwrap();
+
+        // burn cpu on this thread until the task clock has overflowed enough times
+        for _ in 0..1_000 {
+            if LIMITS.load(Ordering::SeqCst) != 0 {
+                break;
+            }
+            ::test::burn_cpu();
+        }
+
+        assert_eq!(LIMITS.load(Ordering::SeqCst), 1);
+        assert!(WAKEUPS.load(Ordering::SeqCst) <= 2);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio1::io::unix::AsyncFd;
+
+use super::{config::SamplingConfig, record::Record, ring_buffer::RingBuffer};
+use error::*;
+
+/// A futures 0.3 `Stream` of records which is driven by a tokio 1.x runtime provided by the
+/// caller, rather than on a dedicated sampler thread. Records are decoded by the same iterator
+/// which serves blocking callers, so the stream yields exactly what `SamplerHandle` would.
+pub struct RecordStream {
+    // NOTE(anp): declared first so that it's deregistered before the buffer closes the fd
+    readiness: AsyncFd<RawFd>,
+    buffer: RingBuffer,
+}
+
+impl RecordStream {
+    /// Opens and enables the events, which all write to one ring buffer. `PidConfig::Current`
+    /// refers to the calling thread.
+    ///
+    /// Must be called from within a tokio 1.x runtime which has IO enabled.
+    pub fn new(sample_configs: Vec<SamplingConfig>) -> Result<Self> {
+        let buffer = RingBuffer::new(sample_configs)?;
+        // NOTE(unsafe) the buffer owns the fd and is dropped after the registration
+        let readiness = unsafe { AsyncFd::register(buffer.as_raw_fd()) }
+            .map_err(::std::io::Error::from)?;
+
+        debug!("enabling our ring buffer's file descriptor");
+        buffer.enable_fd()?;
+
+        Ok(Self { readiness, buffer })
+    }
+}
+
+impl Stream for RecordStream {
+    type Item = Result<Record>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = match this.readiness.poll_read_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(why)) => return Poll::Ready(Some(Err(why.into()))),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if let Some(record) = this.buffer.next() {
+                return Poll::Ready(Some(record));
+            }
+
+            trace!("buffer drained, clearing fd readiness");
+            guard.clear_ready();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sample::config::SamplingRate;
+    use sample::record::RecordContents;
+    use std::future::poll_fn;
+    use {Counted, SwEvent};
+
+    #[test]
+    fn stream_on_caller_runtime() {
+        let rt = ::tokio1::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let _runtime = rt.enter();
+
+        let config = SamplingConfig {
+            event: Counted::Software(SwEvent::TaskClock),
+            rate: SamplingRate::Period(100_000),
+            precise_ip: 0,
+            ..SamplingConfig::default()
+        };
+        let mut stream = RecordStream::new(vec![config]).unwrap();
+
+        let mut samples = 0;
+        while samples < 10 {
+            // burn some cpu on this thread so the task clock has something to sample
+            ::test::burn_cpu();
+
+            let record = rt
+                .block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)))
+                .unwrap()
+                .unwrap();
+            if let RecordContents::Sample(_) = record.contents {
+                samples += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    ptr::{self, NonNull},
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use enum_primitive::FromPrimitive;
+use libc;
+use nix::errno::errno;
+use page_size::get as page_size;
+
+use count::{CountConfig, Counted, Counter};
+use error::*;
+use raw::perf_event_mmap_page;
+use sample::ring_buffer::BufferError;
+use EventConfig;
+
+/// A counter which the calling thread can read with the rdpmc instruction instead of a read(2)
+/// syscall, by mapping the event's metadata page and following the self-monitoring protocol
+/// described in perf_event_open(2). This takes a few instructions rather than a trip through the
+/// kernel, which matters when measuring very small regions of code.
+///
+/// rdpmc is only available for hardware events which are currently scheduled on a PMU, while the
+/// thread measuring itself is running, on x86, and when /sys/devices/cpu/rdpmc allows it. In any
+/// other case `read` falls back to the syscall.
+pub struct SelfMonitoredCounter {
+    counter: Counter,
+    page: NonNull<perf_event_mmap_page>,
+}
+
+// the capabilities bits of perf_event_mmap_page, in the order they're declared
+const CAP_USER_RDPMC: u64 = 1 << 2;
+
+impl SelfMonitoredCounter {
+    /// Opens the event (disabled) and maps its metadata page. `shared.pid` should be
+    /// `PidConfig::Current`, since rdpmc reads the counters of the CPU the caller is running on.
+    pub fn new(event: Counted, shared: EventConfig) -> Result<Self> {
+        let counter = Counter::new(CountConfig { event, shared })?;
+        let fd = match counter.raw_fds()[..] {
+            [fd] => fd,
+            _ => {
+                return Err(Error::Misc {
+                    inner: "a self-monitored counter can't be opened on every CPU".into(),
+                })
+            }
+        };
+
+        // NOTE(unsafe) mapping only the first page gives the metadata without a data section
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size(),
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+
+        if base == libc::MAP_FAILED {
+            Err(BufferError::from_i32(errno()).unwrap())?
+        }
+
+        Ok(Self {
+            counter,
+            page: NonNull::new(base as *mut perf_event_mmap_page).unwrap(),
+        })
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        self.counter.enable()
+    }
+
+    /// Returns true if the kernel currently allows reading this counter with rdpmc. This can
+    /// change while the counter is running, e.g. if the event is moved off of the PMU.
+    pub fn rdpmc_available(&self) -> bool {
+        self.read_page().map(|(_, rdpmc)| rdpmc).unwrap_or(false)
+    }
+
+    /// Reads the counter's value, with rdpmc if possible.
+    pub fn read(&mut self) -> Result<u64> {
+        match self.read_page() {
+            Some((count, true)) => Ok(count),
+            _ => self.counter.read().map(|(_, count)| count),
+        }
+    }
+
+    /// Returns the parameters for converting between the CPU's timestamp counter and perf
+    /// timestamps, if the hardware has a constant and nonstop TSC.
+    pub fn clock(&self) -> Option<PerfClock> {
+        unsafe { PerfClock::from_page(self.page.as_ptr()) }
+    }
+
+    /// Reads the count and whether it came from the PMU, following the seqlock protocol for
+    /// reading the metadata page.
+    fn read_page(&self) -> Option<(u64, bool)> {
+        // NOTE(unsafe) the page is mapped for as long as we are
+        unsafe {
+            read_consistent(self.page.as_ptr(), |page| {
+                let index = ptr::read_volatile(&(*page).index);
+                let offset = ptr::read_volatile(&(*page).offset);
+                let capabilities = ptr::read_volatile(&(*page).__bindgen_anon_1.capabilities);
+                let width = u32::from(ptr::read_volatile(&(*page).pmc_width));
+                let on_pmu = cfg!(any(target_arch = "x86", target_arch = "x86_64"))
+                    && capabilities & CAP_USER_RDPMC != 0
+                    && index != 0
+                    && width != 0;
+
+                let mut count = offset;
+                if on_pmu {
+                    // the counter is only pmc_width bits wide, sign extend it
+                    let pmc = (rdpmc(index - 1) << (64 - width)) as i64 >> (64 - width);
+                    count = count.wrapping_add(pmc);
+                }
+
+                (count as u64, on_pmu)
+            })
+        }
+    }
+}
+
+/// Calls `read` until the metadata page's lock shows that the kernel didn't update the page while
+/// it was being read, or gives up if the kernel keeps updating it.
+///
+/// The kernel updates the page concurrently, so `read` must read every field with
+/// `ptr::read_volatile`.
+pub(crate) unsafe fn read_consistent<T>(
+    page: *const perf_event_mmap_page,
+    mut read: impl FnMut(*const perf_event_mmap_page) -> T,
+) -> Option<T> {
+    for _ in 0..100 {
+        let seq = ptr::read_volatile(&(*page).lock);
+        compiler_fence(Ordering::SeqCst);
+
+        let value = read(page);
+
+        compiler_fence(Ordering::SeqCst);
+        if ptr::read_volatile(&(*page).lock) == seq {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// A snapshot of the parameters the kernel publishes in an event's metadata page for converting
+/// between the hardware clock (the TSC on x86) and perf timestamps, like those in samples with
+/// `SampleRequest::Time`. (since Linux 3.12)
+///
+/// The parameters only change if the kernel recalibrates the TSC, but `time_enabled` and
+/// `time_running` are only accurate until the event is next scheduled in or out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PerfClock {
+    time_shift: u16,
+    time_mult: u32,
+    time_offset: u64,
+    /// Only present if cap_user_time_zero is set.
+    time_zero: Option<u64>,
+    time_enabled: u64,
+    time_running: u64,
+    /// Whether the event was running on the PMU, and so accumulating running time.
+    running: bool,
+}
+
+const CAP_USER_TIME: u64 = 1 << 3;
+const CAP_USER_TIME_ZERO: u64 = 1 << 4;
+
+impl PerfClock {
+    /// Reads the clock parameters from a mapped metadata page, if cap_user_time is set.
+    pub(crate) unsafe fn from_page(page: *const perf_event_mmap_page) -> Option<Self> {
+        read_consistent(page, |page| {
+            let capabilities = ptr::read_volatile(&(*page).__bindgen_anon_1.capabilities);
+            if capabilities & CAP_USER_TIME == 0 {
+                return None;
+            }
+
+            let time_zero = ptr::read_volatile(&(*page).time_zero);
+            Some(PerfClock {
+                time_shift: ptr::read_volatile(&(*page).time_shift),
+                time_mult: ptr::read_volatile(&(*page).time_mult),
+                time_offset: ptr::read_volatile(&(*page).time_offset),
+                time_zero: if capabilities & CAP_USER_TIME_ZERO != 0 {
+                    Some(time_zero)
+                } else {
+                    None
+                },
+                time_enabled: ptr::read_volatile(&(*page).time_enabled),
+                time_running: ptr::read_volatile(&(*page).time_running),
+                running: ptr::read_volatile(&(*page).index) != 0,
+            })
+        })
+        .and_then(|clock| clock)
+    }
+
+    /// Converts a hardware cycle count (e.g. from rdtsc) to a perf timestamp in nanoseconds.
+    /// Returns None if the kernel doesn't publish time_zero.
+    pub fn cycles_to_timestamp(&self, cycles: u64) -> Option<u64> {
+        self.time_zero
+            .map(|time_zero| time_zero.wrapping_add(self.cycles_to_nanos(cycles)))
+    }
+
+    /// Converts a perf timestamp in nanoseconds to a hardware cycle count. Returns None if the
+    /// kernel doesn't publish time_zero.
+    pub fn timestamp_to_cycles(&self, timestamp: u64) -> Option<u64> {
+        let shift = u32::from(self.time_shift);
+        let mult = u64::from(self.time_mult);
+
+        self.time_zero.map(|time_zero| {
+            let time = timestamp.wrapping_sub(time_zero);
+            let (quot, rem) = (time / mult, time % mult);
+            (quot << shift).wrapping_add((rem << shift) / mult)
+        })
+    }
+
+    /// Returns the event's enabled and running times in nanoseconds at the moment the cycle
+    /// counter read `cycles`, which should have been read after this snapshot was taken.
+    pub fn enabled_running(&self, cycles: u64) -> (u64, u64) {
+        let delta = self.time_offset.wrapping_add(self.cycles_to_nanos(cycles));
+
+        let enabled = self.time_enabled.wrapping_add(delta);
+        let running = if self.running {
+            self.time_running.wrapping_add(delta)
+        } else {
+            self.time_running
+        };
+
+        (enabled, running)
+    }
+
+    /// Scales a count which was only measured for `running` out of `enabled` nanoseconds, e.g.
+    /// because the event was multiplexed with others, to estimate the count over all of `enabled`.
+    pub fn scale(count: u64, enabled: u64, running: u64) -> u64 {
+        if running == 0 {
+            return 0;
+        }
+        let (quot, rem) = (count / running, count % running);
+        quot.wrapping_mul(enabled)
+            .wrapping_add(rem.wrapping_mul(enabled) / running)
+    }
+
+    fn cycles_to_nanos(&self, cycles: u64) -> u64 {
+        let shift = u32::from(self.time_shift);
+        let mult = u64::from(self.time_mult);
+
+        let quot = cycles >> shift;
+        let rem = cycles & ((1u64 << shift) - 1);
+        quot.wrapping_mul(mult)
+            .wrapping_add(rem.wrapping_mul(mult) >> shift)
+    }
+}
+
+impl Drop for SelfMonitoredCounter {
+    fn drop(&mut self) {
+        let res = unsafe { libc::munmap(self.page.as_ptr() as *mut libc::c_void, page_size()) };
+        if res != 0 {
+            let why = BufferError::from_i32(errno());
+            warn!("unable to unmap a counter's metadata page: {:?}", why);
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn rdpmc(counter: u32) -> u64 {
+    let (low, high): (u32, u32);
+    ::std::arch::asm!(
+        "rdpmc",
+        in("ecx") counter,
+        out("eax") low,
+        out("edx") high,
+        options(nomem, nostack)
+    );
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+// other architectures read their counters differently, and always fall back to the syscall
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+unsafe fn rdpmc(_counter: u32) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SwEvent;
+
+    fn tsc_clock(time_zero: Option<u64>) -> PerfClock {
+        // a 3GHz TSC, as the kernel would describe it
+        PerfClock {
+            time_shift: 31,
+            time_mult: 715_827_882,
+            time_offset: 0,
+            time_zero,
+            time_enabled: 1_000,
+            time_running: 500,
+            running: true,
+        }
+    }
+
+    #[test]
+    fn clock_conversions() {
+        let clock = tsc_clock(Some(1_000_000));
+
+        // 3 billion cycles is about a second
+        let timestamp = clock.cycles_to_timestamp(3_000_000_000).unwrap();
+        assert!((timestamp as i64 - 1_001_000_000).abs() < 10);
+
+        let cycles = clock.timestamp_to_cycles(timestamp).unwrap();
+        assert!((cycles as i64 - 3_000_000_000).abs() < 10);
+
+        assert_eq!(clock.cycles_to_timestamp(0), Some(1_000_000));
+        assert_eq!(tsc_clock(None).cycles_to_timestamp(0), None);
+
+        let (enabled, running) = clock.enabled_running(3_000);
+        assert!((enabled as i64 - 2_000).abs() < 2);
+        assert!((running as i64 - 1_500).abs() < 2);
+        assert_eq!(PerfClock::scale(10, enabled, running), 10 * enabled / running);
+        assert_eq!(PerfClock::scale(10, 100, 0), 0);
+    }
+
+    #[test]
+    fn falls_back_for_software_events() {
+        let mut counter =
+            SelfMonitoredCounter::new(Counted::Software(SwEvent::TaskClock), EventConfig::default())
+                .unwrap();
+        counter.enable().unwrap();
+
+        // software events are never on the PMU
+        assert!(!counter.rdpmc_available());
+
+        let first = counter.read().unwrap();
+        ::test::burn_cpu();
+        assert!(counter.read().unwrap() > first);
+    }
+}
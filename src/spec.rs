@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+use toml;
+
+use count::Counted;
+use error::*;
+use fallback::Fallback;
+use sample::config::SamplingConfig;
+use {EventConfig, Perf, PerfBuilder};
+
+/// A measurement profile, e.g. kept in a version-controlled TOML or JSON file. Every field is
+/// optional and defaults to the same as the corresponding `Default` impl.
+///
+/// ```toml
+/// [config]
+/// exclude_kernel = true
+///
+/// [[count]]
+/// event = "cpu-cycles"
+/// fallbacks = [{ Event = "cpu-clock" }]
+///
+/// [[count]]
+/// event = "l1d-read-miss"
+///
+/// [[sample]]
+/// event = "cpu-cycles"
+/// rate = { Frequency = 1000 }
+/// requests = ["InstructionPointer", "Callchain"]
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSpec {
+    /// The settings shared by the counted events.
+    pub config: EventConfig,
+    /// The events to count.
+    pub count: Vec<CountSpec>,
+    /// The events to sample, each with its own shared settings.
+    pub sample: Vec<SamplingConfig>,
+}
+
+/// An event to count, and what to try if it can't be opened.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CountSpec {
+    pub event: Counted,
+    #[serde(default)]
+    pub fallbacks: Vec<Fallback>,
+}
+
+impl SessionSpec {
+    /// Reads a spec from a file, parsed as TOML or JSON according to its extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let parse = || -> ::std::result::Result<Self, SpecError> {
+            let parse = match path.extension().and_then(|e| e.to_str()) {
+                Some("toml") => Self::from_toml,
+                Some("json") => Self::from_json,
+                _ => return Err(SpecError::UnknownFormat),
+            };
+            parse(&fs::read_to_string(path)?)
+        };
+
+        parse().map_err(|inner| Error::Spec {
+            path: path.to_owned(),
+            inner,
+        })
+    }
+
+    pub fn from_toml(spec: &str) -> ::std::result::Result<Self, SpecError> {
+        Ok(toml::from_str(spec)?)
+    }
+
+    pub fn from_json(spec: &str) -> ::std::result::Result<Self, SpecError> {
+        Ok(serde_json::from_str(spec)?)
+    }
+
+    /// A builder for the counted events, which can be opened with `create`.
+    pub fn counters(&self) -> PerfBuilder {
+        self.count
+            .iter()
+            .fold(Perf::new(self.config.clone()), |builder, count| {
+                builder.count_with_fallbacks(count.event, count.fallbacks.clone())
+            })
+    }
+}
+
+/// Why a `SessionSpec` couldn't be loaded.
+#[derive(Debug, Error)]
+pub enum SpecError {
+    #[error("failed to read the file")]
+    Read(#[from] io::Error),
+    #[error("invalid TOML")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid JSON")]
+    Json(#[from] serde_json::Error),
+    #[error("unknown format, expected a .toml or .json file")]
+    UnknownFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sample::config::{SampleRequest, SamplingRate};
+    use {CacheId, CacheOpId, CacheOpResultId, HardwareCacheSpec, HwEvent, OpenFlags, SwEvent};
+
+    #[test]
+    fn loads_toml_and_json() {
+        let toml = r#"
+            [config]
+            exclude_kernel = true
+            flags = ["FD_CLOEXEC", "FD_NO_GROUP"]
+
+            [[count]]
+            event = "cpu-cycles"
+            fallbacks = [{ Event = "cpu-clock" }, "ExcludeKernel"]
+
+            [[count]]
+            event = "l1d-read-miss"
+
+            [[sample]]
+            event = "task-clock"
+            rate = { Frequency = 1000 }
+            requests = ["InstructionPointer", "Callchain"]
+        "#;
+        let spec = SessionSpec::from_toml(toml).unwrap();
+
+        assert!(spec.config.exclude_kernel);
+        assert!(
+            spec.config.exclude_guest,
+            "unset fields should be defaulted"
+        );
+        assert_eq!(
+            spec.config.flags,
+            OpenFlags::FD_CLOEXEC | OpenFlags::FD_NO_GROUP
+        );
+        assert_eq!(
+            spec.count,
+            vec![
+                CountSpec {
+                    event: Counted::Hardware(HwEvent::CpuCycles),
+                    fallbacks: vec![
+                        Fallback::Event(Counted::Software(SwEvent::CpuClock)),
+                        Fallback::ExcludeKernel,
+                    ],
+                },
+                CountSpec {
+                    event: Counted::HardwareCache(HardwareCacheSpec(
+                        CacheId::Level1Data,
+                        CacheOpId::Read,
+                        CacheOpResultId::Miss
+                    )),
+                    fallbacks: vec![],
+                },
+            ]
+        );
+        assert_eq!(spec.sample.len(), 1);
+        assert_eq!(spec.sample[0].event, Counted::Software(SwEvent::TaskClock));
+        assert_eq!(spec.sample[0].rate, SamplingRate::Frequency(1000));
+        assert_eq!(
+            spec.sample[0].requests,
+            vec![SampleRequest::InstructionPointer, SampleRequest::Callchain]
+        );
+        assert_eq!(
+            spec.sample[0].precise_ip,
+            SamplingConfig::default().precise_ip
+        );
+
+        // what's serialized can be read back
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(SessionSpec::from_json(&json).unwrap(), spec);
+
+        match SessionSpec::from_toml("[[count]]\nevent = \"l1d-read-hit\"") {
+            Err(SpecError::Toml(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
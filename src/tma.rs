@@ -0,0 +1,437 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Read;
+
+use error::*;
+use fd::{PerfEventAttrThingy, PerfFile};
+use pmu::PmuEvent;
+use raw::perf_event_read_format::*;
+use sample::record::ReadValues;
+use {CpuConfig, EventConfig, PidConfig};
+
+/// The PMUs which may list the topdown events: "cpu", or "cpu_core" for the performance cores of
+/// a hybrid CPU.
+const CORE_PMUS: &[&str] = &["cpu", "cpu_core"];
+
+/// The slots event leads the group, and the kernel reports each metric from the PERF_METRICS MSR
+/// as a share of its count. (Intel Ice Lake and later, since Linux 5.9)
+const PERF_METRICS: &[&str] = &[
+    "slots",
+    "topdown-retiring",
+    "topdown-bad-spec",
+    "topdown-fe-bound",
+    "topdown-be-bound",
+];
+
+/// Added to the `PERF_METRICS` group where listed. (Intel Sapphire Rapids and later, since Linux
+/// 5.15)
+const PERF_METRICS_LEVEL_2: &[&str] = &[
+    "topdown-heavy-ops",
+    "topdown-br-mispredict",
+    "topdown-fetch-lat",
+    "topdown-mem-bound",
+];
+
+/// The cycles and uops events which the classic formula is computed from, with the pipeline
+/// width applied through topdown-total-slots' scale. (Intel Sandy Bridge to Comet Lake, since
+/// Linux 4.8)
+const CLASSIC: &[&str] = &[
+    "topdown-total-slots",
+    "topdown-slots-issued",
+    "topdown-slots-retired",
+    "topdown-fetch-bubbles",
+    "topdown-recovery-bubbles",
+];
+
+/// How a `Topdown` measures the breakdown.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum TopdownMethod {
+    /// The slots and topdown-* metric events, read from the CPU's PERF_METRICS register.
+    PerfMetrics,
+    /// Frontend bound is the share of slots which weren't delivered uops, bad speculation is the
+    /// share of issued uops which didn't retire plus recovery bubbles, retiring is the share of
+    /// retired uops, and backend bound is the rest. Only level 1 can be computed.
+    Classic,
+}
+
+/// Top-down microarchitecture analysis (TMA), which breaks down where a CPU's pipeline slots
+/// went, like `perf stat --topdown`.
+///
+/// The events are opened as a single group, so they're always measured over the same time and
+/// can't be inherited by child tasks. Requires an Intel CPU whose PMU lists topdown events in
+/// sysfs.
+#[derive(Debug)]
+pub struct Topdown {
+    method: TopdownMethod,
+    events: Vec<PmuEvent>,
+    /// The group leader first, then the other events in the same order as `events`.
+    files: Vec<PerfFile>,
+}
+
+impl Topdown {
+    /// Opens the topdown events for `shared.pid` and `shared.cpu`, disabled. Uses the
+    /// PERF_METRICS events if the PMU lists them, including level 2 if it can, and otherwise
+    /// falls back to the classic formula. `shared.inherit` is ignored.
+    ///
+    /// The classic formula can only be used system-wide, with `PidConfig::Other(-1)` and a
+    /// `CpuConfig::Specific`, like `perf stat --topdown`. Its topdown-total-slots event sets the
+    /// AnyThread bit, which counts both hyperthreads of a core and so can't be attributed to a
+    /// task. The kernel also rejects that bit with EACCES unless perf_event_paranoid is 0 or less
+    /// or the process has CAP_PERFMON.
+    pub fn open(shared: EventConfig) -> Result<Self> {
+        let system_wide = shared.pid == PidConfig::Other(-1) && shared.cpu != CpuConfig::All;
+        let mut first_error = None;
+
+        for &pmu in CORE_PMUS {
+            let mut methods = Vec::new();
+            if PERF_METRICS.iter().all(|e| PmuEvent::exists(pmu, e)) {
+                let mut events = PERF_METRICS.to_vec();
+                if PERF_METRICS_LEVEL_2
+                    .iter()
+                    .all(|e| PmuEvent::exists(pmu, e))
+                {
+                    events.extend(PERF_METRICS_LEVEL_2);
+                }
+                methods.push((TopdownMethod::PerfMetrics, events));
+            }
+            if CLASSIC.iter().all(|e| PmuEvent::exists(pmu, e)) {
+                if system_wide {
+                    methods.push((TopdownMethod::Classic, CLASSIC.to_vec()));
+                } else {
+                    first_error.get_or_insert(Error::Misc {
+                        inner: "this CPU's topdown events count both hyperthreads of a core, so \
+                                they can only be opened system-wide, with PidConfig::Other(-1) and \
+                                a CpuConfig::Specific"
+                            .into(),
+                    });
+                }
+            }
+
+            for (method, names) in methods {
+                let res = names
+                    .iter()
+                    .map(|name| PmuEvent::find(pmu, name))
+                    .collect::<Result<Vec<_>>>()
+                    .and_then(|events| Self::open_group(&shared, method, events));
+                match res {
+                    Ok(topdown) => return Ok(topdown),
+                    Err(why) => {
+                        debug!(
+                            "unable to open {}'s topdown events with {:?}: {}",
+                            pmu, method, why
+                        );
+                        first_error.get_or_insert(why);
+                    }
+                }
+            }
+        }
+
+        Err(first_error.unwrap_or_else(|| Error::Misc {
+            inner: "the CPU's PMU doesn't list any topdown events".into(),
+        }))
+    }
+
+    fn open_group(
+        shared: &EventConfig,
+        method: TopdownMethod,
+        events: Vec<PmuEvent>,
+    ) -> Result<Self> {
+        let mut files = Vec::with_capacity(events.len());
+        for event in &events {
+            let mut attr = shared.raw();
+            event.apply(&mut attr);
+            // the kernel rejects inherited events which read their whole group
+            attr.set_inherit(0);
+            attr.set_inherit_stat(0);
+            if files.is_empty() {
+                attr.read_format = Self::READ_FORMAT;
+            } else {
+                // members count whenever the leader does
+                attr.set_disabled(0);
+            }
+
+            let file = PerfFile::open_in_group(
+                &attr,
+                &shared.pid,
+                shared.cpu,
+                shared.flags,
+                files.first(),
+            )?;
+            files.push(file);
+        }
+
+        debug!(
+            "opened topdown events {:?}",
+            events.iter().map(|e| &e.name).collect::<Vec<_>>()
+        );
+        Ok(Topdown {
+            method,
+            events,
+            files,
+        })
+    }
+
+    const READ_FORMAT: u64 = PERF_FORMAT_GROUP as u64
+        | PERF_FORMAT_TOTAL_TIME_ENABLED as u64
+        | PERF_FORMAT_TOTAL_TIME_RUNNING as u64;
+
+    pub fn method(&self) -> TopdownMethod {
+        self.method
+    }
+
+    /// Starts the whole group counting.
+    pub fn enable(&self) -> Result<()> {
+        self.files[0].enable()
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        self.files[0].disable()
+    }
+
+    /// Reads the breakdown of the slots counted so far.
+    pub fn read(&mut self) -> Result<TopdownReport> {
+        // { u64 nr; u64 time_enabled; u64 time_running; u64 values[nr]; }
+        let mut bytes = vec![0; 8 * (3 + self.events.len())];
+        let len = self.files[0].read(&mut bytes)?;
+        let read = ReadValues::from_bytes(&bytes[..len], Self::READ_FORMAT)?;
+
+        let counts = read
+            .values
+            .iter()
+            .zip(&self.events)
+            .map(|(value, event)| value.value as f64 * event.scale)
+            .collect::<Vec<_>>();
+
+        let mut report = TopdownReport::new(self.method, &counts);
+        report.time_enabled = read.time_enabled.unwrap_or(0);
+        report.time_running = read.time_running.unwrap_or(0);
+        Ok(report)
+    }
+}
+
+/// Where a CPU's pipeline slots went. Each breakdown is a fraction between 0 and 1, and
+/// `None` if no slots were counted, e.g. because the group was never scheduled onto the PMU.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct TopdownReport {
+    pub method: TopdownMethod,
+    /// Pipeline slots: the CPU's issue width times its unhalted cycles.
+    pub slots: u64,
+    /// Nanoseconds the group was enabled and running for. They differ if the PMU was multiplexed,
+    /// which doesn't affect the breakdown since the group is always scheduled as a whole.
+    pub time_enabled: u64,
+    pub time_running: u64,
+    pub level1: Option<Level1>,
+    /// Only measured with `TopdownMethod::PerfMetrics` on CPUs which support it.
+    pub level2: Option<Level2>,
+}
+
+/// The top level of the breakdown, which sums to 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Level1 {
+    /// Slots which retired a uop, i.e. useful work.
+    pub retiring: f64,
+    /// Slots wasted on uops which were cancelled, e.g. after a branch misprediction.
+    pub bad_speculation: f64,
+    /// Slots where the frontend didn't deliver a uop while the backend could have accepted one.
+    pub frontend_bound: f64,
+    /// Slots where the backend couldn't accept a uop, e.g. while waiting on memory.
+    pub backend_bound: f64,
+}
+
+/// Each of the level 1 categories split in two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Level2 {
+    pub light_operations: f64,
+    /// Retired uops from instructions which decode to several, or come from the microcode
+    /// sequencer.
+    pub heavy_operations: f64,
+    pub branch_mispredicts: f64,
+    pub machine_clears: f64,
+    /// Frontend stalls from instruction cache or TLB misses, or resteers.
+    pub fetch_latency: f64,
+    /// Frontend stalls from decoder limitations.
+    pub fetch_bandwidth: f64,
+    pub core_bound: f64,
+    /// Backend stalls waiting on the memory hierarchy.
+    pub memory_bound: f64,
+}
+
+impl TopdownReport {
+    /// Computes the breakdown from scaled counts, which are in the order of the method's events.
+    fn new(method: TopdownMethod, counts: &[f64]) -> Self {
+        let count = |i: usize| counts.get(i).cloned().unwrap_or(0.0);
+        let share = |n: f64, d: f64| (n / d).clamp(0.0, 1.0);
+
+        let (slots, level1, level2) = match method {
+            TopdownMethod::PerfMetrics => {
+                // the metrics are each a rounded share of slots, so normalize by their sum
+                let total = count(1) + count(2) + count(3) + count(4);
+                let level1 = if total > 0.0 {
+                    Some(Level1 {
+                        retiring: share(count(1), total),
+                        bad_speculation: share(count(2), total),
+                        frontend_bound: share(count(3), total),
+                        backend_bound: share(count(4), total),
+                    })
+                } else {
+                    None
+                };
+                let level2 = match level1 {
+                    Some(l1) if counts.len() > PERF_METRICS.len() => {
+                        let heavy = share(count(5), total);
+                        let mispredicts = share(count(6), total);
+                        let fetch_latency = share(count(7), total);
+                        let memory = share(count(8), total);
+                        Some(Level2 {
+                            light_operations: (l1.retiring - heavy).max(0.0),
+                            heavy_operations: heavy,
+                            branch_mispredicts: mispredicts,
+                            machine_clears: (l1.bad_speculation - mispredicts).max(0.0),
+                            fetch_latency,
+                            fetch_bandwidth: (l1.frontend_bound - fetch_latency).max(0.0),
+                            core_bound: (l1.backend_bound - memory).max(0.0),
+                            memory_bound: memory,
+                        })
+                    }
+                    _ => None,
+                };
+                (count(0), level1, level2)
+            }
+            TopdownMethod::Classic => {
+                let (slots, issued, retired) = (count(0), count(1), count(2));
+                let (fetch_bubbles, recovery_bubbles) = (count(3), count(4));
+                let level1 = if slots > 0.0 {
+                    let frontend_bound = share(fetch_bubbles, slots);
+                    let bad_speculation = share(issued - retired + recovery_bubbles, slots);
+                    let retiring = share(retired, slots);
+                    Some(Level1 {
+                        retiring,
+                        bad_speculation,
+                        frontend_bound,
+                        backend_bound: (1.0 - frontend_bound - bad_speculation - retiring).max(0.0),
+                    })
+                } else {
+                    None
+                };
+                (slots, level1, None)
+            }
+        };
+
+        TopdownReport {
+            method,
+            slots: slots as u64,
+            time_enabled: 0,
+            time_running: 0,
+            level1,
+            level2,
+        }
+    }
+}
+
+/// Lists each category as a percentage of slots, with level 2 indented under level 1.
+impl Display for TopdownReport {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let l1 = match self.level1 {
+            Some(l1) => l1,
+            None => return f.write_str("no slots were counted\n"),
+        };
+        let line = |f: &mut Formatter, indent: usize, share: f64, name: &str| {
+            writeln!(
+                f,
+                "{:>7.1}%  {:indent$}{}",
+                share * 100.0,
+                "",
+                name,
+                indent = indent
+            )
+        };
+
+        let l2 = self.level2;
+        line(f, 0, l1.retiring, "retiring")?;
+        if let Some(l2) = l2 {
+            line(f, 2, l2.light_operations, "light operations")?;
+            line(f, 2, l2.heavy_operations, "heavy operations")?;
+        }
+        line(f, 0, l1.bad_speculation, "bad speculation")?;
+        if let Some(l2) = l2 {
+            line(f, 2, l2.branch_mispredicts, "branch mispredicts")?;
+            line(f, 2, l2.machine_clears, "machine clears")?;
+        }
+        line(f, 0, l1.frontend_bound, "frontend bound")?;
+        if let Some(l2) = l2 {
+            line(f, 2, l2.fetch_latency, "fetch latency")?;
+            line(f, 2, l2.fetch_bandwidth, "fetch bandwidth")?;
+        }
+        line(f, 0, l1.backend_bound, "backend bound")?;
+        if let Some(l2) = l2 {
+            line(f, 2, l2.core_bound, "core bound")?;
+            line(f, 2, l2.memory_bound, "memory bound")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_breakdowns() {
+        // slots, retiring, bad-spec, fe-bound, be-bound, heavy-ops, br-mispredict, fetch-lat,
+        // mem-bound, with the kernel's rounding leaving the metrics a little short of slots
+        let counts = [1000.0, 396.0, 99.0, 198.0, 297.0, 99.0, 66.0, 148.5, 198.0];
+        let report = TopdownReport::new(TopdownMethod::PerfMetrics, &counts);
+        assert_eq!(report.slots, 1000);
+        assert_eq!(
+            report.level1,
+            Some(Level1 {
+                retiring: 0.4,
+                bad_speculation: 0.1,
+                frontend_bound: 0.2,
+                backend_bound: 0.3,
+            })
+        );
+        let l2 = report.level2.unwrap();
+        assert!((l2.light_operations - 0.3).abs() < 1e-9);
+        assert!((l2.machine_clears - (0.1 - 66.0 / 990.0)).abs() < 1e-9);
+        assert!((l2.fetch_bandwidth - 0.05).abs() < 1e-9);
+        assert!((l2.memory_bound - 0.2).abs() < 1e-9);
+
+        let without_level2 = TopdownReport::new(TopdownMethod::PerfMetrics, &counts[..5]);
+        assert_eq!(without_level2.level1, report.level1);
+        assert_eq!(without_level2.level2, None);
+
+        // total slots (already scaled by the width), issued, retired, fetch bubbles, recovery
+        let report = TopdownReport::new(TopdownMethod::Classic, &[400.0, 180.0, 160.0, 80.0, 20.0]);
+        let l1 = report.level1.unwrap();
+        assert_eq!(l1.frontend_bound, 0.2);
+        assert_eq!(l1.bad_speculation, 0.1);
+        assert_eq!(l1.retiring, 0.4);
+        assert!((l1.backend_bound - 0.3).abs() < 1e-9);
+        assert_eq!(report.level2, None);
+        assert!(report.to_string().contains("   40.0%  retiring\n"));
+
+        let empty = TopdownReport::new(TopdownMethod::Classic, &[0.0; 5]);
+        assert_eq!(empty.level1, None);
+    }
+
+    #[test]
+    fn measures_a_loop() {
+        let mut topdown = match Topdown::open(EventConfig::default()) {
+            Ok(topdown) => topdown,
+            Err(why) => {
+                // most virtual machines and non-Intel CPUs don't have topdown events
+                debug!("skipping, topdown isn't supported: {}", why);
+                return;
+            }
+        };
+        topdown.enable().unwrap();
+
+        ::test::burn_cpu();
+
+        let report = topdown.read().unwrap();
+        assert!(report.slots > 0);
+        let l1 = report.level1.unwrap();
+        let total = l1.retiring + l1.bad_speculation + l1.frontend_bound + l1.backend_bound;
+        assert!((total - 1.0).abs() < 0.01, "{:?}", l1);
+    }
+}